@@ -10,7 +10,9 @@ import SelectCriteria, {
 } from "./routes/select-criteria"
 import {FormStage} from "./types/types"
 import SelectMarkers, {loader as markerLoader} from "./routes/select-markers"
-import VideoOptions from "./routes/video-options"
+import VideoOptions, {
+  loader as videoOptionsLoader,
+} from "./routes/video-options"
 import Progress from "./routes/progress"
 import {nanoid} from "nanoid"
 import {loader as rootLoader} from "./routes/root"
@@ -39,6 +41,7 @@ const router = createBrowserRouter([
       {
         path: "/video-options",
         element: <VideoOptions />,
+        loader: videoOptionsLoader,
       },
       {
         path: "/progress",
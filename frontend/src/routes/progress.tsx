@@ -6,12 +6,31 @@ interface Progress {
   total: number
 }
 
+interface JobError {
+  kind: "ffmpeg" | "io" | "stash" | "other"
+  message: string
+  markerId?: string
+  clip?: string
+  stderr?: string
+}
+
+interface Job {
+  id: string
+  status: "queued" | "running" | "finished" | "failed" | "cancelled"
+  progress: Progress
+  error?: JobError
+}
+
 function Progress() {
   const {state} = useStateMachine()
   const [progress, setProgress] = useState<Progress>()
   const [finished, setFinished] = useState(false)
+  const [cancelled, setCancelled] = useState(false)
+  const [error, setError] = useState<JobError>()
+  const [submitError, setSubmitError] = useState<string>()
 
   const onSubmit = async () => {
+    setSubmitError(undefined)
     const body = JSON.stringify(state.data)
     const response = await fetch("/api/create", {
       method: "POST",
@@ -20,19 +39,39 @@ function Progress() {
     })
 
     if (response.ok) {
-      const es = new EventSource("/api/progress")
+      const es = new EventSource(`/api/progress/${state.data.id}`)
       es.onmessage = (event) => {
-        const data = JSON.parse(event.data) as Progress
-        const isFinished = data.finished === data.total
-        if (isFinished) {
-          setFinished(true)
-          es.close()
-        }
-        setProgress(data)
+        const data = JSON.parse(event.data) as Job
+        setProgress(data.progress)
+      }
+      es.addEventListener("done", () => {
+        setFinished(true)
+        es.close()
+      })
+      es.addEventListener("cancelled", () => {
+        setCancelled(true)
+        es.close()
+      })
+      es.addEventListener("failed", (event) => {
+        const data = JSON.parse((event as MessageEvent).data) as Job
+        setError(data.error)
+        es.close()
+      })
+    } else {
+      // the options are checked before the job is queued
+      const text = await response.text()
+      try {
+        setSubmitError((JSON.parse(text) as {error: string}).error)
+      } catch {
+        setSubmitError(text || response.statusText)
       }
     }
   }
 
+  const onCancel = async () => {
+    await fetch(`/api/jobs/${state.data.id}`, {method: "DELETE"})
+  }
+
   return (
     <div className="mt-8 max-w-lg w-full self-center flex flex-col items-center">
       {!progress && !finished && (
@@ -41,7 +80,19 @@ function Progress() {
         </button>
       )}
 
-      {progress && !finished && (
+      {submitError && (
+        <div className="text-center flex flex-col gap-4 mt-8 w-full">
+          <p className="text-xl">
+            <strong>The video could not be created:</strong>
+          </p>
+          <pre className="text-left text-sm whitespace-pre-wrap bg-base-200 p-2">
+            {submitError}
+          </pre>
+          <p>Go back to change the options and try again.</p>
+        </div>
+      )}
+
+      {progress && !finished && !cancelled && !error && (
         <div className="text-center w-full">
           <progress
             className="progress h-6 progress-primary w-full"
@@ -51,6 +102,32 @@ function Progress() {
           <p>
             {progress.finished} / {progress.total} clips finished
           </p>
+          <button onClick={onCancel} className="btn btn-error mt-4">
+            Cancel
+          </button>
+        </div>
+      )}
+
+      {error && (
+        <div className="text-center flex flex-col gap-4 mt-8 w-full">
+          <p className="text-xl">
+            <strong>Creating the video failed:</strong>
+          </p>
+          {error.clip && (
+            <p>
+              While creating clip <code>{error.clip}</code> (marker{" "}
+              {error.markerId})
+            </p>
+          )}
+          <pre className="text-left text-sm whitespace-pre-wrap bg-base-200 p-2">
+            {error.stderr || error.message}
+          </pre>
+        </div>
+      )}
+
+      {cancelled && (
+        <div className="text-center text-xl mt-8">
+          <p>The compilation was cancelled.</p>
         </div>
       )}
 
@@ -67,6 +144,24 @@ function Progress() {
           >
             Download
           </a>
+          {state.data.chapters !== "off" && (
+            <div className="flex gap-4 justify-center">
+              <a
+                href={`/api/download/${state.data.id}/chapters?format=json`}
+                className="btn btn-outline"
+                download
+              >
+                Chapters (JSON)
+              </a>
+              <a
+                href={`/api/download/${state.data.id}/chapters?format=webvtt`}
+                className="btn btn-outline"
+                download
+              >
+                Chapters (WebVTT)
+              </a>
+            </div>
+          )}
         </div>
       )}
     </div>
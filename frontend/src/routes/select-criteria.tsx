@@ -1,28 +1,76 @@
 import {useStateMachine} from "little-state-machine"
 import {useState} from "react"
-import {useLoaderData, useNavigate} from "react-router-dom"
-import {FormStage, Performer, Tag} from "../types/types"
+import {
+  LoaderFunctionArgs,
+  useLoaderData,
+  useNavigate,
+  useSearchParams,
+} from "react-router-dom"
+import {
+  Exclusions,
+  FormStage,
+  Gender,
+  MarkerFilter,
+  Modifier,
+  Performer,
+  SavedFilter,
+  SceneFilter,
+  Studio,
+  Tag,
+} from "../types/types"
 import {updateForm} from "./actions"
 
 interface Data {
   performers: Performer[]
   tags: Tag[]
+  performerTags: Tag[]
+  studios: Studio[]
+  savedFilters: SavedFilter[]
 }
 
-async function fetchTags(): Promise<Tag[]> {
-  const response = await fetch("/api/tags")
+interface Scene {
+  id: string
+  title?: string
+  fileName?: string
+  date?: string
+  studio?: string
+  rating?: number
+  markerCount: number
+}
+
+async function fetchTags(performers = false): Promise<Tag[]> {
+  const response = await fetch(`/api/tags?performers=${performers}`)
   return await response.json()
 }
 
-async function fetchPerformers(): Promise<Performer[]> {
-  const response = await fetch("/api/performers")
+// the search parameters of the page filter the performer list
+async function fetchPerformers(search: string): Promise<Performer[]> {
+  const response = await fetch(`/api/performers${search}`)
   return await response.json()
 }
 
-export async function loader(): Promise<Data> {
-  const [tags, performers] = await Promise.all([fetchTags(), fetchPerformers()])
+async function fetchSavedFilters(): Promise<SavedFilter[]> {
+  const response = await fetch("/api/saved-filters")
+  return await response.json()
+}
 
-  return {tags, performers}
+async function fetchStudios(): Promise<Studio[]> {
+  const response = await fetch("/api/studios")
+  return await response.json()
+}
+
+export async function loader({request}: LoaderFunctionArgs): Promise<Data> {
+  const search = new URL(request.url).search
+  const [tags, performerTags, performers, studios, savedFilters] =
+    await Promise.all([
+      fetchTags(),
+      fetchTags(true),
+      fetchPerformers(search),
+      fetchStudios(),
+      fetchSavedFilters(),
+    ])
+
+  return {tags, performerTags, performers, studios, savedFilters}
 }
 
 function filterData(data: Data, filter?: string): Data {
@@ -30,6 +78,7 @@ function filterData(data: Data, filter?: string): Data {
     return data
   } else {
     return {
+      ...data,
       performers: data.performers.filter((p) =>
         p.name.toLowerCase().includes(filter.toLowerCase())
       ),
@@ -40,6 +89,103 @@ function filterData(data: Data, filter?: string): Data {
   }
 }
 
+type CriterionKey = "performers" | "tags" | "sceneTags"
+
+const emptyFilter: Required<Omit<MarkerFilter, "exclusions">> = {
+  performers: {ids: [], modifier: "INCLUDES"},
+  tags: {ids: [], modifier: "INCLUDES"},
+  sceneTags: {ids: [], modifier: "INCLUDES"},
+}
+
+const noExclusions: Exclusions = {tags: [], sceneTags: [], performers: []}
+
+const noSceneFilter: SceneFilter = {studios: []}
+
+const genders: {id: Gender; name: string}[] = [
+  {id: "FEMALE", name: "Female"},
+  {id: "MALE", name: "Male"},
+  {id: "TRANSGENDER_FEMALE", name: "Transgender female"},
+  {id: "TRANSGENDER_MALE", name: "Transgender male"},
+  {id: "INTERSEX", name: "Intersex"},
+  {id: "NON_BINARY", name: "Non-binary"},
+]
+
+const listParam = (value: string | null) =>
+  value === null ? undefined : value.split(",").filter((v) => v.length > 0)
+
+function isSceneFilterEmpty(filter: SceneFilter) {
+  return (
+    filter.studios.length === 0 &&
+    Object.entries(filter).every(
+      ([key, value]) => key === "studios" || value === undefined
+    )
+  )
+}
+
+const optionalNumber = (value: string) =>
+  value === "" ? undefined : Number(value)
+
+function MultiSelect({
+  label,
+  options,
+  value,
+  onChange,
+}: {
+  label: string
+  options: {id: string; name: string}[]
+  value: string[]
+  onChange: (ids: string[]) => void
+}) {
+  return (
+    <div className="form-control">
+      <label className="label">
+        <span className="label-text">{label}</span>
+      </label>
+      <select
+        multiple
+        className="select select-bordered h-32"
+        value={value}
+        onChange={(e) =>
+          onChange(Array.from(e.target.selectedOptions, (o) => o.value))
+        }
+      >
+        {options.map((option) => (
+          <option key={option.id} value={option.id}>
+            {option.name}
+          </option>
+        ))}
+      </select>
+    </div>
+  )
+}
+
+function ModifierSelect({
+  label,
+  value,
+  onChange,
+}: {
+  label: string
+  value: Modifier
+  onChange: (modifier: Modifier) => void
+}) {
+  return (
+    <div className="form-control">
+      <label className="label">
+        <span className="label-text">{label}</span>
+      </label>
+      <select
+        className="select select-bordered select-sm"
+        value={value}
+        onChange={(e) => onChange(e.target.value as Modifier)}
+      >
+        <option value="INCLUDES">Any of the selected</option>
+        <option value="INCLUDES_ALL">All of the selected</option>
+        <option value="EXCLUDES">None of the selected</option>
+      </select>
+    </div>
+  )
+}
+
 function SelectCriteria() {
   const data = useLoaderData() as Data
   const [filter, setFilter] = useState("")
@@ -48,8 +194,93 @@ function SelectCriteria() {
   const [selection, setSelection] = useState<string[]>(
     state.data.selectedIds || []
   )
+  const [combined, setCombined] = useState<
+    Required<Omit<MarkerFilter, "exclusions">>
+  >(() => ({
+    ...emptyFilter,
+    ...state.data.filter,
+  }))
+  const [exclusions, setExclusions] = useState<Exclusions>(
+    () => state.data.filter?.exclusions || noExclusions
+  )
+  const [sceneFilter, setSceneFilter] = useState<SceneFilter>(
+    () => state.data.sceneFilter || noSceneFilter
+  )
+  const [scenes, setScenes] = useState<Scene[]>()
   const queryType = state.data.selectMode
   const navigate = useNavigate()
+  const [searchParams, setSearchParams] = useSearchParams()
+  const performerGenders = listParam(searchParams.get("genders")) as
+    | Gender[]
+    | undefined
+
+  const updateSearchParam = (key: string, value?: string) => {
+    setSearchParams(
+      (params) => {
+        if (value === undefined) {
+          params.delete(key)
+        } else {
+          params.set(key, value)
+        }
+        return params
+      },
+      {replace: true}
+    )
+  }
+
+  const onCombinedChange = (
+    key: CriterionKey,
+    id: string,
+    checked: boolean
+  ) => {
+    setCombined((filter) => {
+      const ids = filter[key].ids.filter((i) => i !== id)
+      return {
+        ...filter,
+        [key]: {...filter[key], ids: checked ? [...ids, id] : ids},
+      }
+    })
+  }
+
+  const onModifierChange = (key: CriterionKey, modifier: Modifier) => {
+    setCombined((filter) => ({...filter, [key]: {...filter[key], modifier}}))
+  }
+
+  const onDepthChange = (depth?: number) => {
+    setCombined((filter) => ({
+      ...filter,
+      tags: {...filter.tags, depth},
+      sceneTags: {...filter.sceneTags, depth},
+    }))
+  }
+
+  const hasExclusions =
+    exclusions.tags.length > 0 ||
+    exclusions.sceneTags.length > 0 ||
+    exclusions.performers.length > 0
+  const hasSceneFilter = !isSceneFilterEmpty(sceneFilter)
+  const combinedEmpty =
+    combined.performers.ids.length === 0 &&
+    combined.tags.ids.length === 0 &&
+    combined.sceneTags.ids.length === 0 &&
+    !hasExclusions &&
+    !hasSceneFilter
+
+  const updateSceneFilter = (update: Partial<SceneFilter>) => {
+    setSceneFilter((filter) => ({...filter, ...update}))
+    setScenes(undefined)
+  }
+
+  const onPreviewScenes = async () => {
+    const response = await fetch("/api/scenes", {
+      method: "POST",
+      body: JSON.stringify(sceneFilter),
+      headers: {"Content-Type": "application/json"},
+    })
+    if (response.ok) {
+      setScenes(await response.json())
+    }
+  }
 
   const onCheckboxChange = (id: string, checked: boolean) => {
     if (checked) {
@@ -60,11 +291,34 @@ function SelectCriteria() {
   }
 
   const onNextStage = () => {
-    actions.updateForm({
-      stage: FormStage.SelectMarkers,
-      selectedIds: selection,
-      selectedMarkers: undefined,
-    })
+    if (queryType === "combined") {
+      // leave out criteria without any IDs, they would match nothing
+      const filter: MarkerFilter = {
+        performers:
+          combined.performers.ids.length > 0 ? combined.performers : undefined,
+        tags: combined.tags.ids.length > 0 ? combined.tags : undefined,
+        sceneTags:
+          combined.sceneTags.ids.length > 0 ? combined.sceneTags : undefined,
+        exclusions,
+      }
+      actions.updateForm({
+        stage: FormStage.SelectMarkers,
+        selectedIds: [],
+        filter,
+        sceneFilter: hasSceneFilter ? sceneFilter : undefined,
+        performerGenders,
+        selectedMarkers: undefined,
+      })
+    } else {
+      actions.updateForm({
+        stage: FormStage.SelectMarkers,
+        selectedIds: selection,
+        filter: hasExclusions ? {exclusions} : undefined,
+        sceneFilter: hasSceneFilter ? sceneFilter : undefined,
+        performerGenders,
+        selectedMarkers: undefined,
+      })
+    }
     navigate("/select-markers")
   }
 
@@ -83,12 +337,61 @@ function SelectCriteria() {
             type="button"
             onClick={onNextStage}
             className="btn btn-success"
-            disabled={selection.length === 0}
+            disabled={
+              queryType === "combined" ? combinedEmpty : selection.length === 0
+            }
           >
             Next
           </button>
         </div>
       )}
+      {queryType && (
+        <section className="grid grid-cols-3 gap-4 w-full mb-4">
+          <MultiSelect
+            label="Performer genders (listed and named in labels):"
+            options={genders}
+            value={performerGenders || []}
+            onChange={(ids) =>
+              updateSearchParam(
+                "genders",
+                ids.length > 0 ? ids.join(",") : undefined
+              )
+            }
+          />
+          {(queryType === "performers" || queryType === "combined") && (
+            <>
+              <MultiSelect
+                label="Only list performers with any of these tags:"
+                options={data.performerTags}
+                value={listParam(searchParams.get("tags")) || []}
+                onChange={(ids) =>
+                  updateSearchParam(
+                    "tags",
+                    ids.length > 0 ? ids.join(",") : undefined
+                  )
+                }
+              />
+              <div className="form-control">
+                <label className="label cursor-pointer justify-start gap-2">
+                  <input
+                    type="checkbox"
+                    className="checkbox checkbox-primary"
+                    checked={searchParams.get("favoritesOnly") === "true"}
+                    onChange={(e) =>
+                      updateSearchParam(
+                        "favoritesOnly",
+                        e.target.checked ? "true" : undefined
+                      )
+                    }
+                  />
+                  <span className="label-text">Only list favorites</span>
+                </label>
+              </div>
+            </>
+          )}
+        </section>
+      )}
+
       {state.data.selectMode === "performers" && (
         <section className="grid grid-cols-4 gap-2 w-full">
           {performers.map((performer) => (
@@ -151,6 +454,321 @@ function SelectCriteria() {
           ))}
         </section>
       )}
+
+      {state.data.selectMode === "savedFilter" && (
+        <section className="flex flex-col gap-2 w-full">
+          {data.savedFilters.length === 0 && (
+            <p>There are no saved scene marker filters in Stash.</p>
+          )}
+          {data.savedFilters.map((savedFilter) => (
+            <label
+              key={savedFilter.id}
+              className="label cursor-pointer justify-start gap-2"
+            >
+              <input
+                type="radio"
+                name="saved-filter"
+                className="radio radio-primary"
+                checked={selection.includes(savedFilter.id)}
+                onChange={() => setSelection([savedFilter.id])}
+              />
+              <span className="label-text">{savedFilter.name}</span>
+            </label>
+          ))}
+        </section>
+      )}
+
+      {state.data.selectMode === "combined" && (
+        <section className="flex flex-col gap-4 w-full">
+          <div className="flex gap-4 items-end">
+            <ModifierSelect
+              label="Performers"
+              value={combined.performers.modifier}
+              onChange={(m) => onModifierChange("performers", m)}
+            />
+            <ModifierSelect
+              label="Marker tags"
+              value={combined.tags.modifier}
+              onChange={(m) => onModifierChange("tags", m)}
+            />
+            <ModifierSelect
+              label="Scene tags"
+              value={combined.sceneTags.modifier}
+              onChange={(m) => onModifierChange("sceneTags", m)}
+            />
+            <div className="form-control">
+              <label className="label">
+                <span className="label-text">
+                  Include child tags (levels, -1 for all):
+                </span>
+              </label>
+              <input
+                type="number"
+                min="-1"
+                className="input input-bordered input-sm"
+                value={combined.tags.depth ?? 0}
+                onChange={(e) =>
+                  onDepthChange(
+                    e.target.value === "0" || e.target.value === ""
+                      ? undefined
+                      : Number(e.target.value)
+                  )
+                }
+              />
+            </div>
+          </div>
+
+          <h2 className="text-xl">Performers</h2>
+          <div className="grid grid-cols-4 gap-2 w-full">
+            {performers.map((performer) => (
+              <label
+                key={performer.id}
+                className="label cursor-pointer justify-start gap-2"
+              >
+                <input
+                  type="checkbox"
+                  className="checkbox checkbox-primary"
+                  checked={combined.performers.ids.includes(performer.id)}
+                  onChange={(e) =>
+                    onCombinedChange(
+                      "performers",
+                      performer.id,
+                      e.target.checked
+                    )
+                  }
+                />
+                <span className="label-text">{performer.name}</span>
+              </label>
+            ))}
+          </div>
+
+          <h2 className="text-xl">Tags</h2>
+          <div className="grid grid-cols-4 gap-2 w-full">
+            {tags.map((tag) => (
+              <div key={tag.id} className="flex flex-col">
+                <span className="font-bold">{tag.name}</span>
+                <label className="label cursor-pointer justify-start gap-2">
+                  <input
+                    type="checkbox"
+                    className="checkbox checkbox-primary checkbox-sm"
+                    checked={combined.tags.ids.includes(tag.id)}
+                    onChange={(e) =>
+                      onCombinedChange("tags", tag.id, e.target.checked)
+                    }
+                  />
+                  <span className="label-text">On the marker</span>
+                </label>
+                <label className="label cursor-pointer justify-start gap-2">
+                  <input
+                    type="checkbox"
+                    className="checkbox checkbox-secondary checkbox-sm"
+                    checked={combined.sceneTags.ids.includes(tag.id)}
+                    onChange={(e) =>
+                      onCombinedChange("sceneTags", tag.id, e.target.checked)
+                    }
+                  />
+                  <span className="label-text">On the scene</span>
+                </label>
+              </div>
+            ))}
+          </div>
+        </section>
+      )}
+
+      {queryType && (
+        <section className="flex flex-col gap-2 w-full mt-8">
+          <h2 className="text-xl">Leave out markers with</h2>
+          <div className="grid grid-cols-3 gap-4">
+            <MultiSelect
+              label="Any of these marker tags:"
+              options={data.tags}
+              value={exclusions.tags}
+              onChange={(tags) => setExclusions((e) => ({...e, tags}))}
+            />
+            <MultiSelect
+              label="Any of these scene tags:"
+              options={data.tags}
+              value={exclusions.sceneTags}
+              onChange={(sceneTags) =>
+                setExclusions((e) => ({...e, sceneTags}))
+              }
+            />
+            <MultiSelect
+              label="Any of these performers in the scene:"
+              options={data.performers}
+              value={exclusions.performers}
+              onChange={(performers) =>
+                setExclusions((e) => ({...e, performers}))
+              }
+            />
+          </div>
+        </section>
+      )}
+
+      {queryType && (
+        <section className="flex flex-col gap-2 w-full mt-8">
+          <h2 className="text-xl">Only use markers from scenes</h2>
+          <div className="grid grid-cols-3 gap-4">
+            <MultiSelect
+              label="From any of these studios:"
+              options={data.studios}
+              value={sceneFilter.studios}
+              onChange={(studios) => updateSceneFilter({studios})}
+            />
+            <div className="flex flex-col">
+              <div className="form-control">
+                <label className="label">
+                  <span className="label-text">Lowest rating:</span>
+                </label>
+                <select
+                  className="select select-bordered select-sm"
+                  value={sceneFilter.minRating ?? ""}
+                  onChange={(e) =>
+                    updateSceneFilter({
+                      minRating: optionalNumber(e.target.value),
+                    })
+                  }
+                >
+                  <option value="">Any</option>
+                  <option value="20">1 star</option>
+                  <option value="40">2 stars</option>
+                  <option value="60">3 stars</option>
+                  <option value="80">4 stars</option>
+                  <option value="100">5 stars</option>
+                </select>
+              </div>
+              <div className="form-control">
+                <label className="label">
+                  <span className="label-text">Released from:</span>
+                </label>
+                <input
+                  type="date"
+                  className="input input-bordered input-sm"
+                  value={sceneFilter.dateFrom ?? ""}
+                  onChange={(e) =>
+                    updateSceneFilter({dateFrom: e.target.value || undefined})
+                  }
+                />
+              </div>
+              <div className="form-control">
+                <label className="label">
+                  <span className="label-text">Released until:</span>
+                </label>
+                <input
+                  type="date"
+                  className="input input-bordered input-sm"
+                  value={sceneFilter.dateTo ?? ""}
+                  onChange={(e) =>
+                    updateSceneFilter({dateTo: e.target.value || undefined})
+                  }
+                />
+              </div>
+              <div className="form-control">
+                <label className="label">
+                  <span className="label-text">Organized:</span>
+                </label>
+                <select
+                  className="select select-bordered select-sm"
+                  value={
+                    sceneFilter.organized === undefined
+                      ? ""
+                      : String(sceneFilter.organized)
+                  }
+                  onChange={(e) =>
+                    updateSceneFilter({
+                      organized:
+                        e.target.value === ""
+                          ? undefined
+                          : e.target.value === "true",
+                    })
+                  }
+                >
+                  <option value="">Either</option>
+                  <option value="true">Yes</option>
+                  <option value="false">No</option>
+                </select>
+              </div>
+            </div>
+            <div className="flex flex-col">
+              <div className="form-control">
+                <label className="label">
+                  <span className="label-text">Lowest O-counter:</span>
+                </label>
+                <input
+                  type="number"
+                  min="0"
+                  className="input input-bordered input-sm"
+                  value={sceneFilter.minOCounter ?? ""}
+                  onChange={(e) =>
+                    updateSceneFilter({
+                      minOCounter: optionalNumber(e.target.value),
+                    })
+                  }
+                />
+              </div>
+              <div className="form-control">
+                <label className="label">
+                  <span className="label-text">Lowest play count:</span>
+                </label>
+                <input
+                  type="number"
+                  min="0"
+                  className="input input-bordered input-sm"
+                  value={sceneFilter.minPlayCount ?? ""}
+                  onChange={(e) =>
+                    updateSceneFilter({
+                      minPlayCount: optionalNumber(e.target.value),
+                    })
+                  }
+                />
+              </div>
+              <div className="form-control">
+                <label className="label">
+                  <span className="label-text">Highest play count:</span>
+                </label>
+                <input
+                  type="number"
+                  min="0"
+                  className="input input-bordered input-sm"
+                  value={sceneFilter.maxPlayCount ?? ""}
+                  onChange={(e) =>
+                    updateSceneFilter({
+                      maxPlayCount: optionalNumber(e.target.value),
+                    })
+                  }
+                />
+              </div>
+            </div>
+          </div>
+          <div className="flex items-center gap-4">
+            <button
+              type="button"
+              className="btn btn-sm"
+              disabled={!hasSceneFilter}
+              onClick={onPreviewScenes}
+            >
+              Preview scenes
+            </button>
+            {scenes && (
+              <span>
+                {scenes.length} scenes with{" "}
+                {scenes.reduce((sum, s) => sum + s.markerCount, 0)} markers
+              </span>
+            )}
+          </div>
+          {scenes && scenes.length > 0 && (
+            <ul className="max-h-64 overflow-y-auto">
+              {scenes.map((scene) => (
+                <li key={scene.id}>
+                  {scene.title || scene.fileName}
+                  {scene.studio && ` - ${scene.studio}`}
+                  {scene.date && ` (${scene.date})`}
+                </li>
+              ))}
+            </ul>
+          )}
+        </section>
+      )}
     </div>
   )
 }
@@ -1,7 +1,7 @@
 import {useStateMachine} from "little-state-machine"
 import {useState} from "react"
 import {LoaderFunction, useLoaderData, useNavigate} from "react-router-dom"
-import {FormStage, FormState} from "../types/types"
+import {FormStage, FormState, MarkerWindow} from "../types/types"
 import {updateForm} from "./actions"
 import {formatDistance} from "date-fns"
 
@@ -28,11 +28,44 @@ export const loader: LoaderFunction = async () => {
   const json = sessionStorage.getItem("form-state")
   if (json) {
     const state: {data: FormState} = JSON.parse(json)
-    const params = new URLSearchParams()
-    params.set("selectedIds", state.data.selectedIds!.join(","))
-    params.set("mode", state.data.selectMode!)
-    const url = `/api/markers?${params.toString()}`
-    const response = await fetch(url)
+    let response
+    const {selectMode, selectedIds, sceneFilter, performerGenders} = state.data
+    if (selectMode === "combined" || sceneFilter) {
+      let filter = state.data.filter
+      if (selectMode === "tags") {
+        filter = {...filter, tags: {ids: selectedIds!, modifier: "INCLUDES"}}
+      } else if (selectMode === "performers") {
+        filter = {
+          ...filter,
+          performers: {ids: selectedIds!, modifier: "INCLUDES"},
+        }
+      } else if (selectMode === "savedFilter") {
+        filter = {...filter, savedFilter: selectedIds![0]}
+      }
+      response = await fetch("/api/markers", {
+        method: "POST",
+        body: JSON.stringify({
+          ...filter,
+          sceneFilter,
+          genders: performerGenders,
+        }),
+        headers: {"Content-Type": "application/json"},
+      })
+    } else {
+      const params = new URLSearchParams()
+      params.set("selectedIds", state.data.selectedIds!.join(","))
+      params.set("mode", state.data.selectMode!)
+      if (performerGenders) {
+        params.set("genders", performerGenders.join(","))
+      }
+      const exclusions = state.data.filter?.exclusions
+      if (exclusions) {
+        params.set("excludedTags", exclusions.tags.join(","))
+        params.set("excludedSceneTags", exclusions.sceneTags.join(","))
+        params.set("excludedPerformers", exclusions.performers.join(","))
+      }
+      response = await fetch(`/api/markers?${params.toString()}`)
+    }
     const markers = await response.json()
     return {markers} satisfies Data
   } else {
@@ -40,7 +73,9 @@ export const loader: LoaderFunction = async () => {
   }
 }
 
-function getDuration({start, end}: Marker): number {
+function getDuration(marker: Marker, window?: MarkerWindow): number {
+  const start = window?.start ?? marker.start
+  const end = window?.end ?? marker.end
   if (end) {
     return end - start
   } else {
@@ -71,6 +106,9 @@ function SelectMarkers() {
   const [selection, setSelection] = useState(
     () => state.data.selectedMarkers || data.markers.dtos.map((m) => m.id)
   )
+  const [windows, setWindows] = useState<Record<string, MarkerWindow>>(
+    () => state.data.markerWindows || {}
+  )
   const [filter, setFilter] = useState("")
   const [videoPreview, setVideoPreview] = useState<string>()
   const navigate = useNavigate()
@@ -87,7 +125,10 @@ function SelectMarkers() {
   const totalDuration = formatDuration(
     markers
       .filter((m) => selection.includes(m.id))
-      .reduce((total, marker) => total + getDuration(marker), 0)
+      .reduce(
+        (total, marker) => total + getDuration(marker, windows[marker.id]),
+        0
+      )
   )
 
   const onCheckboxChange = (id: string, checked: boolean) => {
@@ -98,10 +139,22 @@ function SelectMarkers() {
     }
   }
 
+  const onWindowChange = (
+    id: string,
+    key: keyof MarkerWindow,
+    value: number
+  ) => {
+    setWindows((w) => ({
+      ...w,
+      [id]: {...w[id], [key]: isNaN(value) ? undefined : value},
+    }))
+  }
+
   const onNextStage = () => {
     actions.updateForm({
       stage: FormStage.VideoOptions,
       selectedMarkers: selection,
+      markerWindows: windows,
       markers: data.markers.gql,
     })
     navigate("/video-options")
@@ -155,8 +208,32 @@ function SelectMarkers() {
               </p>
               <p>
                 <strong>Duration: </strong>
-                {formatDuration(getDuration(marker))}
+                {formatDuration(getDuration(marker, windows[marker.id]))}
               </p>
+              <div className="flex gap-2">
+                <input
+                  type="number"
+                  min="0"
+                  title="Start (seconds)"
+                  className="input input-bordered input-sm w-1/2"
+                  placeholder={`Start: ${marker.start}`}
+                  value={windows[marker.id]?.start ?? ""}
+                  onChange={(e) =>
+                    onWindowChange(marker.id, "start", e.target.valueAsNumber)
+                  }
+                />
+                <input
+                  type="number"
+                  min="0"
+                  title="End (seconds)"
+                  className="input input-bordered input-sm w-1/2"
+                  placeholder={`End: ${marker.end ?? "auto"}`}
+                  value={windows[marker.id]?.end ?? ""}
+                  onChange={(e) =>
+                    onWindowChange(marker.id, "end", e.target.valueAsNumber)
+                  }
+                />
+              </div>
               <div className="card-actions justify-between">
                 <div className="form-control">
                   <label className="label cursor-pointer">
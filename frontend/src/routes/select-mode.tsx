@@ -7,7 +7,9 @@ function SelectMode() {
   const {actions} = useStateMachine({updateForm})
   const navigate = useNavigate()
 
-  const onNextStage = (mode: "performers" | "tags") => {
+  const onNextStage = (
+    mode: "performers" | "tags" | "combined" | "savedFilter"
+  ) => {
     actions.updateForm({
       stage: FormStage.SelectCriteria,
       selectMode: mode,
@@ -19,7 +21,10 @@ function SelectMode() {
     <section className="py-4 flex flex-col">
       <div className="flex flex-col items-start gap-4">
         <div className="flex w-full items-center justify-between">
-          <span>You can filter markers either by performers or by tags.</span>
+          <span>
+            You can filter markers by performers, by tags, or by both at once,
+            or use a marker filter you saved in Stash.
+          </span>
         </div>
         <div className="self-center flex gap-2">
           <button
@@ -35,6 +40,20 @@ function SelectMode() {
           >
             Tags
           </button>
+
+          <button
+            className="btn btn-lg btn-secondary w-48"
+            onClick={() => onNextStage("combined")}
+          >
+            Both
+          </button>
+
+          <button
+            className="btn btn-lg btn-secondary w-48"
+            onClick={() => onNextStage("savedFilter")}
+          >
+            Saved filter
+          </button>
         </div>
       </div>
     </section>
@@ -1,30 +1,193 @@
 import {useStateMachine} from "little-state-machine"
 import {useForm} from "react-hook-form"
-import {useNavigate} from "react-router-dom"
-import {FormStage, FormState} from "../types/types"
+import {LoaderFunction, useLoaderData, useNavigate} from "react-router-dom"
+import {
+  BackgroundMusic,
+  FormStage,
+  FormState,
+  LoudnessNormalization,
+  LowerThird,
+  TextPosition,
+  Transition,
+} from "../types/types"
 import {updateForm} from "./actions"
 
 type Inputs = Pick<
   FormState,
-  "clipDuration" | "clipOrder" | "outputFps" | "outputResolution"
->
+  | "targetDuration"
+  | "clipDuration"
+  | "clipStrategy"
+  | "clipOrder"
+  | "outputFps"
+  | "outputResolution"
+  | "encodingProfile"
+  | "chapters"
+> & {
+  // "none" is only used in the form, the request leaves out the transition instead
+  transition?: {kind: "none" | Transition["kind"]; duration: number}
+  loudness?: Omit<LoudnessNormalization, "mode"> & {
+    mode: "none" | LoudnessNormalization["mode"]
+  }
+  // one path per line
+  musicFiles: string
+  musicMode: BackgroundMusic["mode"]
+  musicVolume: number
+  font: string
+  // empty texts and no fields leave out the cards and the lower third
+  introText: string
+  outroText: string
+  cardDuration: number
+  cardPosition: TextPosition
+  cardSize?: number
+  lowerThird: LowerThird
+  // 0 keeps the clip durations, otherwise clips change every this many beats
+  beatsPerClip: number
+}
+
+interface EncodingProfile {
+  name: string
+  description: string
+  available: boolean
+}
 
 const defaultOptions: Inputs = {
   clipDuration: 15,
+  clipStrategy: {type: "random", seed: 123456789},
   clipOrder: "scene-order",
   outputFps: 30,
   outputResolution: "720",
+  encodingProfile: "default",
+  chapters: "marker",
+  transition: {kind: "none", duration: 0.5},
+  loudness: {mode: "none", integrated: -16, truePeak: -1.5},
+  musicFiles: "",
+  musicMode: "mix",
+  musicVolume: 0.5,
+  beatsPerClip: 0,
+  font: "Sans",
+  introText: "",
+  outroText: "",
+  cardDuration: 3,
+  cardPosition: "center",
+  lowerThird: {fields: [], duration: 4, position: "bottom-left"},
+}
+
+export const loader: LoaderFunction = async () => {
+  const response = await fetch("/api/encoding-profiles")
+  const profiles: EncodingProfile[] = await response.json()
+  return profiles
 }
 
 function VideoOptions() {
   const {actions, state} = useStateMachine({updateForm})
   const navigate = useNavigate()
-  const {register, handleSubmit} = useForm<Inputs>({
-    defaultValues: {...defaultOptions, ...state.data},
+  const profiles = useLoaderData() as EncodingProfile[]
+  const {register, handleSubmit, watch} = useForm<Inputs>({
+    defaultValues: {
+      ...defaultOptions,
+      ...state.data,
+      transition: state.data.transition || defaultOptions.transition,
+      loudness: state.data.loudness || defaultOptions.loudness,
+      musicFiles: state.data.music?.files.join("\n") || "",
+      musicMode: state.data.music?.mode || defaultOptions.musicMode,
+      musicVolume: state.data.music?.volume ?? defaultOptions.musicVolume,
+      beatsPerClip:
+        state.data.beatSync?.beatsPerClip ?? defaultOptions.beatsPerClip,
+      font: state.data.overlays?.font || defaultOptions.font,
+      introText: state.data.overlays?.intro?.text || "",
+      outroText: state.data.overlays?.outro?.text || "",
+      cardDuration:
+        state.data.overlays?.intro?.duration ??
+        state.data.overlays?.outro?.duration ??
+        defaultOptions.cardDuration,
+      cardPosition:
+        state.data.overlays?.intro?.position ||
+        state.data.overlays?.outro?.position ||
+        defaultOptions.cardPosition,
+      cardSize:
+        state.data.overlays?.intro?.size ?? state.data.overlays?.outro?.size,
+      lowerThird:
+        state.data.overlays?.lowerThird || defaultOptions.lowerThird,
+    },
   })
 
-  const onSubmit = (values: Inputs) => {
-    actions.updateForm({...values, stage: FormStage.Wait})
+  const strategy = watch("clipStrategy.type")
+  const transitionKind = watch("transition.kind")
+  const loudnessMode = watch("loudness.mode")
+  // empty size inputs use the default size for the resolution
+  const optionalNumber = (value: string) =>
+    value === "" ? undefined : Number(value)
+
+  const onSubmit = ({
+    musicFiles,
+    musicMode,
+    musicVolume,
+    beatsPerClip,
+    font,
+    introText,
+    outroText,
+    cardDuration,
+    cardPosition,
+    cardSize,
+    lowerThird,
+    ...values
+  }: Inputs) => {
+    const files = musicFiles
+      .split("\n")
+      .map((f) => f.trim())
+      .filter((f) => f.length > 0)
+    const music =
+      files.length > 0
+        ? {files, mode: musicMode, volume: musicVolume}
+        : undefined
+    // transitions can't be combined with cutting on the beat
+    const beatSync =
+      music && beatsPerClip > 0 && values.transition?.kind === "none"
+        ? {beatsPerClip}
+        : undefined
+    const card = (text: string) =>
+      text.trim().length > 0
+        ? {
+            text,
+            duration: cardDuration,
+            position: cardPosition,
+            size: cardSize,
+          }
+        : undefined
+    const overlays = {
+      font,
+      intro: card(introText),
+      outro: card(outroText),
+      lowerThird: lowerThird.fields.length > 0 ? lowerThird : undefined,
+    }
+    const duration = values.targetDuration?.duration
+    const targetDuration =
+      duration && !isNaN(duration) ? values.targetDuration : undefined
+    // tags are grouped in the order they were selected in
+    const tagOrder =
+      state.data.selectMode === "tags" ? state.data.selectedIds : undefined
+    const {kind, duration: transitionDuration} = values.transition!
+    const transition =
+      kind === "none" ? undefined : {kind, duration: transitionDuration}
+    const {mode: loudnessMode, ...loudnessTarget} = values.loudness!
+    const loudness =
+      loudnessMode === "none"
+        ? undefined
+        : {mode: loudnessMode, ...loudnessTarget}
+    actions.updateForm({
+      ...values,
+      targetDuration,
+      transition,
+      loudness,
+      overlays:
+        overlays.intro || overlays.outro || overlays.lowerThird
+          ? overlays
+          : undefined,
+      music,
+      beatSync,
+      tagOrder,
+      stage: FormStage.Wait,
+    })
     navigate("/progress")
   }
 
@@ -52,6 +215,79 @@ function VideoOptions() {
             />
           </div>
 
+          <div className="form-control">
+            <label className="label">
+              <span className="label-text">Clips per marker:</span>
+            </label>
+            <select
+              className="select select-bordered"
+              {...register("clipStrategy.type")}
+            >
+              <option value="random">Random lengths</option>
+              <option value="equalSlices">Equal slices</option>
+              <option value="single">Single clip from the start</option>
+              <option value="evenlySpaced">Evenly spaced clips</option>
+              <option value="wholeMarker">Whole marker</option>
+            </select>
+          </div>
+
+          {strategy === "random" && (
+            <div className="form-control">
+              <label className="label">
+                <span className="label-text">Random seed:</span>
+              </label>
+              <input
+                type="number"
+                className="input input-bordered"
+                {...register("clipStrategy.seed", {valueAsNumber: true})}
+              />
+            </div>
+          )}
+
+          {strategy === "evenlySpaced" && (
+            <div className="form-control">
+              <label className="label">
+                <span className="label-text">Number of clips:</span>
+              </label>
+              <input
+                type="number"
+                min="1"
+                className="input input-bordered"
+                {...register("clipStrategy.count", {valueAsNumber: true})}
+              />
+            </div>
+          )}
+
+          <div className="form-control">
+            <label className="label">
+              <span className="label-text">
+                Target total duration (in seconds, optional):
+              </span>
+            </label>
+            <input
+              type="number"
+              min="1"
+              placeholder="Use all clips"
+              className="input input-bordered"
+              {...register("targetDuration.duration", {valueAsNumber: true})}
+            />
+          </div>
+
+          <div className="form-control">
+            <label className="label">
+              <span className="label-text">
+                Time per marker with a target duration:
+              </span>
+            </label>
+            <select
+              className="select select-bordered"
+              {...register("targetDuration.weighting.type")}
+            >
+              <option value="equal">Equal</option>
+              <option value="sceneRating">By scene rating</option>
+            </select>
+          </div>
+
           <div className="form-control">
             <label className="label">
               <span className="label-text">Output resolution:</span>
@@ -96,6 +332,359 @@ function VideoOptions() {
               </option>
               <option value="scene-order">Scene order</option>
               <option value="random">Random</option>
+              <option value="no-consecutive-scenes">
+                Random, without consecutive clips from the same scene
+              </option>
+              <option value="tag">By tag</option>
+              <option value="performer">By performer</option>
+              <option value="scene-date">By scene date</option>
+              <option value="rating">By scene rating</option>
+              <option value="play-count">By play count</option>
+            </select>
+          </div>
+
+          <div className="form-control">
+            <label className="label">
+              <span className="label-text">Transitions between clips:</span>
+            </label>
+            <select
+              className="select select-bordered"
+              {...register("transition.kind")}
+            >
+              <option value="none">None (fastest)</option>
+              <option value="crossfade">Crossfade</option>
+              <option value="fade-through-black">Fade through black</option>
+              <option value="wipe">Wipe</option>
+              <option value="dip-to-white">Dip to white</option>
+            </select>
+          </div>
+
+          {transitionKind !== "none" && (
+            <div className="form-control">
+              <label className="label">
+                <span className="label-text">
+                  Transition duration (in seconds):
+                </span>
+              </label>
+              <input
+                type="number"
+                step="0.1"
+                min="0.1"
+                className="input input-bordered"
+                {...register("transition.duration", {valueAsNumber: true})}
+              />
+            </div>
+          )}
+
+          <div className="form-control">
+            <label className="label">
+              <span className="label-text">
+                Background music (paths to audio files, one per line):
+              </span>
+            </label>
+            <textarea
+              className="textarea textarea-bordered"
+              placeholder="No music"
+              {...register("musicFiles")}
+            />
+          </div>
+
+          <div className="form-control">
+            <label className="label">
+              <span className="label-text">Music mode:</span>
+            </label>
+            <select
+              className="select select-bordered"
+              {...register("musicMode")}
+            >
+              <option value="mix">Mix with the original audio</option>
+              <option value="duck">Lower when the original audio is loud</option>
+              <option value="replace">Replace the original audio</option>
+            </select>
+          </div>
+
+          <div className="form-control">
+            <label className="label">
+              <span className="label-text">Music volume (0 to 1):</span>
+            </label>
+            <input
+              type="number"
+              step="0.05"
+              min="0"
+              max="1"
+              className="input input-bordered"
+              {...register("musicVolume", {valueAsNumber: true})}
+            />
+          </div>
+
+          <div className="form-control">
+            <label className="label">
+              <span className="label-text">
+                Cut on the beat of the music, every N beats (0 to turn off, not
+                available with transitions):
+              </span>
+            </label>
+            <input
+              type="number"
+              min="0"
+              className="input input-bordered"
+              disabled={transitionKind !== "none"}
+              {...register("beatsPerClip", {valueAsNumber: true})}
+            />
+          </div>
+
+          <div className="form-control">
+            <label className="label">
+              <span className="label-text">Intro card text:</span>
+            </label>
+            <input
+              type="text"
+              placeholder="No intro"
+              className="input input-bordered"
+              {...register("introText")}
+            />
+          </div>
+
+          <div className="form-control">
+            <label className="label">
+              <span className="label-text">Outro card text:</span>
+            </label>
+            <input
+              type="text"
+              placeholder="No outro"
+              className="input input-bordered"
+              {...register("outroText")}
+            />
+          </div>
+
+          <div className="form-control">
+            <label className="label">
+              <span className="label-text">
+                Intro and outro duration (in seconds):
+              </span>
+            </label>
+            <input
+              type="number"
+              step="0.5"
+              min="0.5"
+              className="input input-bordered"
+              {...register("cardDuration", {valueAsNumber: true})}
+            />
+          </div>
+
+          <div className="form-control">
+            <label className="label">
+              <span className="label-text">
+                Intro and outro text position:
+              </span>
+            </label>
+            <select
+              className="select select-bordered"
+              {...register("cardPosition")}
+            >
+              <option value="top-left">Top left</option>
+              <option value="top">Top</option>
+              <option value="top-right">Top right</option>
+              <option value="center">Center</option>
+              <option value="bottom-left">Bottom left</option>
+              <option value="bottom">Bottom</option>
+              <option value="bottom-right">Bottom right</option>
+            </select>
+          </div>
+
+          <div className="form-control">
+            <label className="label">
+              <span className="label-text">
+                Intro and outro font size (in pixels, optional):
+              </span>
+            </label>
+            <input
+              type="number"
+              min="1"
+              placeholder="Depends on the resolution"
+              className="input input-bordered"
+              {...register("cardSize", {setValueAs: optionalNumber})}
+            />
+          </div>
+
+          <div className="form-control">
+            <span className="label-text">Show at the start of every clip:</span>
+            <label className="label cursor-pointer">
+              <span className="label-text">Scene title</span>
+              <input
+                type="checkbox"
+                value="scene-title"
+                className="checkbox"
+                {...register("lowerThird.fields")}
+              />
+            </label>
+            <label className="label cursor-pointer">
+              <span className="label-text">Performers</span>
+              <input
+                type="checkbox"
+                value="performers"
+                className="checkbox"
+                {...register("lowerThird.fields")}
+              />
+            </label>
+            <label className="label cursor-pointer">
+              <span className="label-text">Studio</span>
+              <input
+                type="checkbox"
+                value="studio"
+                className="checkbox"
+                {...register("lowerThird.fields")}
+              />
+            </label>
+            <label className="label cursor-pointer">
+              <span className="label-text">Marker tag</span>
+              <input
+                type="checkbox"
+                value="tag"
+                className="checkbox"
+                {...register("lowerThird.fields")}
+              />
+            </label>
+          </div>
+
+          <div className="form-control">
+            <label className="label">
+              <span className="label-text">
+                How long to show it (in seconds):
+              </span>
+            </label>
+            <input
+              type="number"
+              step="0.5"
+              min="0.5"
+              className="input input-bordered"
+              {...register("lowerThird.duration", {valueAsNumber: true})}
+            />
+          </div>
+
+          <div className="form-control">
+            <label className="label">
+              <span className="label-text">Where to show it:</span>
+            </label>
+            <select
+              className="select select-bordered"
+              {...register("lowerThird.position")}
+            >
+              <option value="top-left">Top left</option>
+              <option value="top">Top</option>
+              <option value="top-right">Top right</option>
+              <option value="center">Center</option>
+              <option value="bottom-left">Bottom left</option>
+              <option value="bottom">Bottom</option>
+              <option value="bottom-right">Bottom right</option>
+            </select>
+          </div>
+
+          <div className="form-control">
+            <label className="label">
+              <span className="label-text">
+                Font size at the start of every clip (in pixels, optional):
+              </span>
+            </label>
+            <input
+              type="number"
+              min="1"
+              placeholder="Depends on the resolution"
+              className="input input-bordered"
+              {...register("lowerThird.size", {setValueAs: optionalNumber})}
+            />
+          </div>
+
+          <div className="form-control">
+            <label className="label">
+              <span className="label-text">
+                Font (file path or name, for all text):
+              </span>
+            </label>
+            <input
+              type="text"
+              className="input input-bordered"
+              {...register("font")}
+            />
+          </div>
+
+          <div className="form-control">
+            <label className="label">
+              <span className="label-text">Chapters:</span>
+            </label>
+            <select
+              className="select select-bordered"
+              {...register("chapters")}
+            >
+              <option value="marker">One per marker</option>
+              <option value="scene">One per scene</option>
+              <option value="off">No chapters</option>
+            </select>
+          </div>
+
+          <div className="form-control">
+            <label className="label">
+              <span className="label-text">Loudness normalization:</span>
+            </label>
+            <select
+              className="select select-bordered"
+              {...register("loudness.mode")}
+            >
+              <option value="none">None</option>
+              <option value="output">Whole video</option>
+              <option value="per-clip">Every clip on its own</option>
+            </select>
+          </div>
+
+          {loudnessMode !== "none" && (
+            <>
+              <div className="form-control">
+                <label className="label">
+                  <span className="label-text">Target loudness (LUFS):</span>
+                </label>
+                <input
+                  type="number"
+                  step="0.5"
+                  min="-70"
+                  max="-5"
+                  className="input input-bordered"
+                  {...register("loudness.integrated", {valueAsNumber: true})}
+                />
+              </div>
+
+              <div className="form-control">
+                <label className="label">
+                  <span className="label-text">True peak limit (dBTP):</span>
+                </label>
+                <input
+                  type="number"
+                  step="0.1"
+                  min="-9"
+                  max="0"
+                  className="input input-bordered"
+                  {...register("loudness.truePeak", {valueAsNumber: true})}
+                />
+              </div>
+            </>
+          )}
+
+          <div className="form-control">
+            <label className="label">
+              <span className="label-text">Encoding profile:</span>
+            </label>
+            <select
+              className="select select-bordered"
+              {...register("encodingProfile")}
+            >
+              {profiles.map((profile) => (
+                <option
+                  key={profile.name}
+                  value={profile.name}
+                  disabled={!profile.available}
+                >
+                  {profile.name} ({profile.description})
+                </option>
+              ))}
             </select>
           </div>
         </div>
@@ -4,11 +4,140 @@ export interface Tag {
   count: number
 }
 
+export type Gender =
+  | "MALE"
+  | "FEMALE"
+  | "TRANSGENDER_MALE"
+  | "TRANSGENDER_FEMALE"
+  | "INTERSEX"
+  | "NON_BINARY"
+
 export interface Performer {
   name: string
   id: string
   sceneCount: number
   imageUrl?: string
+  gender?: Gender
+}
+
+export interface SavedFilter {
+  id: string
+  name: string
+}
+
+export interface Studio {
+  name: string
+  id: string
+  sceneCount: number
+}
+
+export type ClipStrategy =
+  | {type: "random"; seed: number}
+  | {type: "equalSlices"}
+  | {type: "single"}
+  | {type: "evenlySpaced"; count: number}
+  | {type: "wholeMarker"}
+
+export interface TargetDuration {
+  duration: number
+  tolerance?: number
+  weighting?:
+    | {type: "equal"}
+    | {type: "sceneRating"}
+    | {type: "tags"; weights: Record<string, number>}
+    | {type: "performers"; weights: Record<string, number>}
+}
+
+export interface Transition {
+  kind: "crossfade" | "fade-through-black" | "wipe" | "dip-to-white"
+  duration: number
+}
+
+export interface BackgroundMusic {
+  files: string[]
+  mode: "replace" | "mix" | "duck"
+  volume: number
+}
+
+export interface BeatSync {
+  beatsPerClip: number
+}
+
+export interface LoudnessNormalization {
+  mode: "per-clip" | "output"
+  integrated: number
+  truePeak: number
+}
+
+export type TextPosition =
+  | "top-left"
+  | "top"
+  | "top-right"
+  | "center"
+  | "bottom-left"
+  | "bottom"
+  | "bottom-right"
+
+export interface TitleCard {
+  text: string
+  duration: number
+  position: TextPosition
+  size?: number
+}
+
+export interface LowerThird {
+  fields: ("scene-title" | "performers" | "studio" | "tag")[]
+  duration: number
+  position: TextPosition
+  size?: number
+}
+
+export interface Overlays {
+  font: string
+  intro?: TitleCard
+  lowerThird?: LowerThird
+  outro?: TitleCard
+}
+
+export type Modifier = "INCLUDES" | "INCLUDES_ALL" | "EXCLUDES"
+
+export interface Criterion {
+  ids: string[]
+  modifier: Modifier
+}
+
+export interface TagCriterion extends Criterion {
+  depth?: number
+}
+
+export interface Exclusions {
+  tags: string[]
+  sceneTags: string[]
+  performers: string[]
+}
+
+export interface MarkerFilter {
+  performers?: Criterion
+  tags?: TagCriterion
+  sceneTags?: TagCriterion
+  exclusions?: Exclusions
+  savedFilter?: string
+}
+
+export interface SceneFilter {
+  studios: string[]
+  minRating?: number
+  dateFrom?: string
+  dateTo?: string
+  organized?: boolean
+  minOCounter?: number
+  minPlayCount?: number
+  maxPlayCount?: number
+}
+
+export interface MarkerWindow {
+  start?: number
+  end?: number
 }
 
 export enum FormStage {
@@ -20,12 +149,35 @@ export enum FormStage {
 }
 
 export interface FormState {
-  selectMode?: "tags" | "performers"
+  selectMode?: "tags" | "performers" | "combined" | "savedFilter"
   selectedIds?: string[]
-  clipOrder?: "random" | "scene-order"
+  filter?: MarkerFilter
+  sceneFilter?: SceneFilter
+  clipOrder?:
+    | "random"
+    | "scene-order"
+    | "tag"
+    | "performer"
+    | "scene-date"
+    | "rating"
+    | "play-count"
+    | "no-consecutive-scenes"
+  orderSeed?: number
+  tagOrder?: string[]
   clipDuration?: number
+  clipStrategy?: ClipStrategy
+  targetDuration?: TargetDuration
+  transition?: Transition
+  music?: BackgroundMusic
+  beatSync?: BeatSync
+  loudness?: LoudnessNormalization
+  overlays?: Overlays
+  chapters?: "marker" | "scene" | "off"
+  performerGenders?: Gender[]
   outputResolution?: "720" | "1080" | "4K"
   outputFps?: number
+  encodingProfile?: string
+  markerWindows?: Record<string, MarkerWindow>
   selectedMarkers?: string[]
   markers?: unknown[]
   stage: FormStage
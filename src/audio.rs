@@ -0,0 +1,81 @@
+use camino::Utf8PathBuf;
+use serde::{Deserialize, Serialize};
+
+/// Sample rate the music is converted to before mixing.
+const MUSIC_SAMPLE_RATE: u32 = 48000;
+
+/// How long the music fades out at the end of the video, in seconds.
+const MUSIC_FADE_OUT: f64 = 3.0;
+
+/// Music played under the whole compilation.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BackgroundMusic {
+    /// Audio files, played one after another and repeated until the video ends.
+    pub files: Vec<Utf8PathBuf>,
+    #[serde(default)]
+    pub mode: MusicMode,
+    /// Volume of the music between 0 and 1. When mixing, the original audio gets the rest,
+    /// when ducking it stays at full volume.
+    #[serde(default = "default_music_volume")]
+    pub volume: f64,
+}
+
+fn default_music_volume() -> f64 {
+    0.5
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum MusicMode {
+    /// Only the music is audible.
+    Replace,
+    /// Music and original audio are mixed at a fixed ratio.
+    #[default]
+    Mix,
+    /// Like `Mix`, but the music gets quieter whenever the original audio is loud.
+    Duck,
+}
+
+/// Builds the filter graph that puts the music under the video's audio. Input 0 is the
+/// video, followed by `music_inputs` audio inputs that are played in order, skipping the
+/// first `music_start` seconds. The output is labelled `[a]`.
+pub fn music_filter(
+    music: &BackgroundMusic,
+    music_inputs: usize,
+    music_start: f64,
+    video_duration: f64,
+) -> String {
+    let volume = music.volume.clamp(0.0, 1.0);
+    let fade_start = (video_duration - MUSIC_FADE_OUT).max(0.0);
+
+    let mut filters = vec![];
+    let mut playlist = String::new();
+    for i in 1..=music_inputs {
+        filters.push(format!(
+            "[{i}:a]aformat=sample_rates={MUSIC_SAMPLE_RATE}:channel_layouts=stereo[m{i}]"
+        ));
+        playlist.push_str(&format!("[m{i}]"));
+    }
+    filters.push(format!(
+        "{playlist}concat=n={music_inputs}:v=0:a=1,\
+         atrim=start={music_start:.3}:duration={video_duration:.3},asetpts=PTS-STARTPTS,\
+         afade=t=out:st={fade_start:.3}:d={MUSIC_FADE_OUT},volume={volume:.2}[music]"
+    ));
+
+    match music.mode {
+        MusicMode::Replace => filters.push("[music]anull[a]".into()),
+        MusicMode::Mix => filters.push(format!(
+            "[0:a]volume={:.2}[original];\
+             [original][music]amix=inputs=2:duration=first:normalize=0[a]",
+            1.0 - volume
+        )),
+        MusicMode::Duck => filters.push(
+            "[0:a]asplit=2[original][sidechain];\
+             [music][sidechain]sidechaincompress=threshold=0.05:ratio=8:attack=20:release=500[ducked];\
+             [original][ducked]amix=inputs=2:duration=first:normalize=0[a]"
+                .into(),
+        ),
+    }
+    filters.join(";\n")
+}
@@ -0,0 +1,294 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clips::TargetDuration,
+    stash_api::find_markers_query::FindMarkersQueryFindSceneMarkersSceneMarkers as Marker,
+};
+
+/// Sample rate the music is decoded at for the analysis.
+pub const ANALYSIS_SAMPLE_RATE: u32 = 11025;
+
+const FRAME_SIZE: usize = 1024;
+const HOP_SIZE: usize = 256;
+const MIN_BPM: f64 = 60.0;
+const MAX_BPM: f64 = 200.0;
+
+/// Cuts the compilation on the beat of the background music.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct BeatSync {
+    /// How many beats every clip lasts.
+    pub beats_per_clip: u32,
+}
+
+/// Tempo and phase of a piece of music.
+#[derive(Debug, Clone, Copy)]
+pub struct BeatGrid {
+    pub bpm: f64,
+    /// Time of the first beat, in seconds.
+    pub first_beat: f64,
+}
+
+impl BeatGrid {
+    pub fn beat_length(&self) -> f64 {
+        60.0 / self.bpm
+    }
+}
+
+/// How strongly the loudness rises in every analysis frame.
+fn onset_envelope(samples: &[f32]) -> Vec<f64> {
+    let mut previous: Option<f64> = None;
+    samples
+        .windows(FRAME_SIZE)
+        .step_by(HOP_SIZE)
+        .map(|frame| {
+            let energy: f64 = frame.iter().map(|s| (*s as f64).powi(2)).sum();
+            let loudness = (energy + 1e-10).ln();
+            let onset = previous.map_or(0.0, |p| (loudness - p).max(0.0));
+            previous = Some(loudness);
+            onset
+        })
+        .collect()
+}
+
+/// Spreads every onset over a few frames. A beat rarely lasts a whole number of frames,
+/// so without this the autocorrelation only matches half the onsets at the right lag
+/// and prefers twice the lag.
+fn smooth(envelope: &[f64]) -> Vec<f64> {
+    const KERNEL: [f64; 5] = [1.0, 2.0, 3.0, 2.0, 1.0];
+    (0..envelope.len())
+        .map(|i| {
+            KERNEL
+                .iter()
+                .enumerate()
+                .filter_map(|(k, weight)| {
+                    let index = (i + k).checked_sub(KERNEL.len() / 2)?;
+                    envelope.get(index).map(|e| e * weight)
+                })
+                .sum()
+        })
+        .collect()
+}
+
+/// Sum of the envelope at every beat of the grid, interpolating between frames.
+fn grid_strength(envelope: &[f64], period: f64, phase: f64) -> f64 {
+    let mut position = phase;
+    let mut strength = 0.0;
+    while position < (envelope.len() - 1) as f64 {
+        let index = position as usize;
+        let fraction = position - index as f64;
+        strength += envelope[index] * (1.0 - fraction) + envelope[index + 1] * fraction;
+        position += period;
+    }
+    strength
+}
+
+/// Estimates the tempo from the autocorrelation of the onset envelope, preferring
+/// tempos around 120 BPM, then refines tempo and phase by matching a beat grid
+/// against the whole envelope.
+pub fn detect_beats(samples: &[f32], sample_rate: u32) -> Option<BeatGrid> {
+    let envelope = onset_envelope(samples);
+    let frame_rate = sample_rate as f64 / HOP_SIZE as f64;
+    let min_lag = (frame_rate * 60.0 / MAX_BPM).floor() as usize;
+    let max_lag = (frame_rate * 60.0 / MIN_BPM).ceil() as usize;
+    if envelope.len() < max_lag * 4 {
+        return None;
+    }
+
+    let smoothed = smooth(&envelope);
+    let (coarse_lag, _) = (min_lag..=max_lag)
+        .map(|lag| {
+            let correlation: f64 = smoothed
+                .iter()
+                .zip(&smoothed[lag..])
+                .map(|(a, b)| a * b)
+                .sum();
+            let bpm = 60.0 * frame_rate / lag as f64;
+            let preference = (-0.5 * (bpm / 120.0).log2().powi(2)).exp();
+            (lag, correlation * preference)
+        })
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))?;
+
+    // a small error in the period adds up over a whole song, so search around the
+    // coarse estimate in steps of a hundredth of a frame.
+    let mut best = (0.0, coarse_lag as f64, 0.0);
+    for step in -100..=100 {
+        let period = coarse_lag as f64 + step as f64 / 100.0;
+        let mut phase = 0.0;
+        while phase < period {
+            let strength = grid_strength(&envelope, period, phase);
+            if strength > best.0 {
+                best = (strength, period, phase);
+            }
+            phase += 0.5;
+        }
+    }
+
+    let (_, period, phase) = best;
+    // the loudness of a frame rises when an onset enters its window, so the onset
+    // happened during the last hop of that window.
+    let onset_offset = (FRAME_SIZE - HOP_SIZE / 2) as f64;
+    let first_beat = (phase * HOP_SIZE as f64 + onset_offset) / sample_rate as f64;
+    Some(BeatGrid {
+        bpm: 60.0 * frame_rate / period,
+        first_beat,
+    })
+}
+
+/// Lengths of the clips in frames, so that clip `n` starts at the frame closest to
+/// beat `n * beats_per_clip`. Rounding each clip separately would let the video drift
+/// away from the music.
+pub fn clip_frames(grid: &BeatGrid, beats_per_clip: u32, clip_count: usize, fps: u32) -> Vec<u32> {
+    let clip_length = grid.beat_length() * beats_per_clip as f64;
+    let boundary = |n: usize| (n as f64 * clip_length * fps as f64).round() as u32;
+    (0..clip_count)
+        .map(|n| boundary(n + 1) - boundary(n))
+        .collect()
+}
+
+/// Cuts every marker's `[start, end)` window into consecutive slots that last
+/// `beats_per_clip` beats, rounded up to whole seconds. Markers shorter than a slot get
+/// no clips. With a target duration, only as many slots as fit are kept, taking the
+/// first slot of every marker, then the second one and so on.
+pub fn fit_clips_to_beat(
+    grid: &BeatGrid,
+    beat_sync: &BeatSync,
+    target: Option<&TargetDuration>,
+    markers: &mut [(&Marker, Vec<(u32, u32)>)],
+    windows: &[(u32, u32)],
+) {
+    let clip_length = grid.beat_length() * beat_sync.beats_per_clip as f64;
+    let duration = clip_length.ceil().max(1.0) as u32;
+    for ((marker, offsets), (start, end)) in markers.iter_mut().zip(windows) {
+        *offsets = (*start..)
+            .step_by(duration as usize)
+            .take_while(|offset| offset + duration <= *end)
+            .map(|offset| (offset, duration))
+            .collect();
+        if offsets.is_empty() {
+            tracing::info!(
+                "marker {} is shorter than {duration} seconds, skipping it",
+                marker.id
+            );
+        }
+    }
+
+    if let Some(target) = target {
+        let clip_count = ((target.duration as f64 / clip_length).round() as usize).max(1);
+        let mut keep = vec![0; markers.len()];
+        let mut kept = 0;
+        let mut round = 0;
+        while kept < clip_count && markers.iter().any(|(_, o)| o.len() > round) {
+            for (i, (_, offsets)) in markers.iter().enumerate() {
+                if kept < clip_count && offsets.len() > round {
+                    keep[i] += 1;
+                    kept += 1;
+                }
+            }
+            round += 1;
+        }
+        for ((_, offsets), keep) in markers.iter_mut().zip(keep) {
+            offsets.truncate(keep);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clips::{tests::marker, Weighting};
+
+    /// Short 1 kHz clicks at the given tempo, the first one after `offset` seconds.
+    fn click_track(bpm: f64, offset: f64, seconds: f64) -> Vec<f32> {
+        let sample_rate = ANALYSIS_SAMPLE_RATE as f64;
+        let mut samples = vec![0.0; (seconds * sample_rate) as usize];
+        let mut beat = offset;
+        while beat < seconds {
+            let start = (beat * sample_rate) as usize;
+            for i in 0..(sample_rate * 0.02) as usize {
+                let t = i as f64 / sample_rate;
+                let click = (t * 1000.0 * std::f64::consts::TAU).sin() * (-t * 200.0).exp();
+                if let Some(sample) = samples.get_mut(start + i) {
+                    *sample = click as f32;
+                }
+            }
+            beat += 60.0 / bpm;
+        }
+        samples
+    }
+
+    #[test]
+    fn detects_the_beat_of_a_click_track() {
+        for (bpm, offset) in [(120.0, 0.25), (95.0, 0.1), (140.0, 0.4)] {
+            let samples = click_track(bpm, offset, 30.0);
+            let grid = detect_beats(&samples, ANALYSIS_SAMPLE_RATE).unwrap();
+            assert!((grid.bpm - bpm).abs() < 0.5, "detected {} BPM", grid.bpm);
+            // the first detected beat may be any of the clicks, but it has to be on one
+            let phase = (grid.first_beat - offset).rem_euclid(grid.beat_length());
+            let error = phase.min(grid.beat_length() - phase);
+            assert!(error < 0.03, "first beat at {}s", grid.first_beat);
+        }
+    }
+
+    #[test]
+    fn needs_enough_music() {
+        let samples = click_track(120.0, 0.0, 2.0);
+        assert!(detect_beats(&samples, ANALYSIS_SAMPLE_RATE).is_none());
+    }
+
+    #[test]
+    fn clip_frames_do_not_drift() {
+        // 130 BPM with 2 beats per clip is 27.69 frames at 30 fps
+        let grid = BeatGrid {
+            bpm: 130.0,
+            first_beat: 0.0,
+        };
+        let frames = clip_frames(&grid, 2, 13, 30);
+        assert!(frames.iter().all(|f| *f == 27 || *f == 28));
+        // 13 clips take exactly 12 seconds
+        assert_eq!(frames.iter().sum::<u32>(), 360);
+
+        let grid = BeatGrid {
+            bpm: 120.0,
+            first_beat: 0.3,
+        };
+        assert_eq!(clip_frames(&grid, 4, 3, 25), vec![50, 50, 50]);
+    }
+
+    #[test]
+    fn fit_clips_slices_marker_windows() {
+        let grid = BeatGrid {
+            bpm: 120.0,
+            first_beat: 0.0,
+        };
+        let beat_sync = BeatSync { beats_per_clip: 4 };
+        let (a, b, c) = (marker("1", None), marker("2", None), marker("3", None));
+        let mut markers = vec![(&a, vec![(10, 30)]), (&b, vec![(100, 30)]), (&c, vec![])];
+        let windows = [(10, 17), (100, 105), (50, 51)];
+        fit_clips_to_beat(&grid, &beat_sync, None, &mut markers, &windows);
+        assert_eq!(markers[0].1, vec![(10, 2), (12, 2), (14, 2)]);
+        assert_eq!(markers[1].1, vec![(100, 2), (102, 2)]);
+        // shorter than a clip
+        assert!(markers[2].1.is_empty());
+    }
+
+    #[test]
+    fn fit_clips_takes_turns_for_a_target_duration() {
+        let grid = BeatGrid {
+            bpm: 120.0,
+            first_beat: 0.0,
+        };
+        let beat_sync = BeatSync { beats_per_clip: 4 };
+        let target = TargetDuration {
+            duration: 6,
+            tolerance: 0,
+            weighting: Weighting::Equal,
+        };
+        let (a, b) = (marker("1", None), marker("2", None));
+        let mut markers = vec![(&a, vec![]), (&b, vec![])];
+        let windows = [(0, 20), (40, 44)];
+        fit_clips_to_beat(&grid, &beat_sync, Some(&target), &mut markers, &windows);
+        assert_eq!(markers[0].1, vec![(0, 2), (2, 2)]);
+        assert_eq!(markers[1].1, vec![(40, 2)]);
+    }
+}
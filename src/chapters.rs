@@ -0,0 +1,195 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{clips::Clip, ffmpeg::formatted_scene, performers::Gender};
+
+/// What a chapter of the compilation covers.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChapterMode {
+    /// Consecutive clips from the same marker.
+    #[default]
+    Marker,
+    /// Consecutive clips from the same scene.
+    Scene,
+    /// No chapters.
+    Off,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Chapter {
+    pub title: String,
+    /// In seconds.
+    pub start: f64,
+    pub end: f64,
+    /// Not set for the intro and outro.
+    pub scene_id: Option<String>,
+    pub marker_ids: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ChapterFormat {
+    #[default]
+    Json,
+    Webvtt,
+}
+
+/// Groups the clips into chapters. `boundaries` holds the start of every clip in the
+/// output, followed by the end of the last one. Titles name the performers with one
+/// of the given genders.
+pub fn chapters(
+    clips: &[Clip<'_>],
+    boundaries: &[f64],
+    mode: ChapterMode,
+    genders: &[Gender],
+) -> Vec<Chapter> {
+    let mut chapters: Vec<Chapter> = vec![];
+    if mode == ChapterMode::Off {
+        return chapters;
+    }
+
+    for (index, clip) in clips.iter().enumerate() {
+        let (start, end) = (boundaries[index], boundaries[index + 1]);
+        let marker = clip.marker;
+        let tag = &marker.primary_tag.name;
+        if let Some(last) = chapters.last_mut() {
+            let same = match mode {
+                ChapterMode::Marker => last.marker_ids.last() == Some(&marker.id),
+                _ => last.scene_id.as_ref() == Some(&marker.scene.id),
+            };
+            if same {
+                last.end = end;
+                if !last.marker_ids.contains(&marker.id) {
+                    last.marker_ids.push(marker.id.clone());
+                    if !last.title.contains(tag.as_str()) {
+                        last.title.push_str(&format!(", {tag}"));
+                    }
+                }
+                continue;
+            }
+        }
+        chapters.push(Chapter {
+            title: format!("{} - {tag}", formatted_scene(marker, genders)),
+            start,
+            end,
+            scene_id: Some(marker.scene.id.clone()),
+            marker_ids: vec![marker.id.clone()],
+        });
+    }
+    chapters
+}
+
+/// Escapes the characters that have a meaning in ffmetadata files.
+fn escape_metadata(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The chapters in ffmpeg's metadata format, with millisecond timestamps.
+pub fn ffmetadata(chapters: &[Chapter]) -> String {
+    let mut metadata = String::from(";FFMETADATA1\n");
+    for chapter in chapters {
+        metadata.push_str(&format!(
+            "\n[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\ntitle={}\n",
+            (chapter.start * 1000.0).round() as u64,
+            (chapter.end * 1000.0).round() as u64,
+            escape_metadata(&chapter.title)
+        ));
+    }
+    metadata
+}
+
+fn vtt_timestamp(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// Escapes the cue text of a WebVTT chapter, which can't contain markup or blank lines.
+fn escape_vtt(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\n', " ")
+}
+
+/// The chapters as a WebVTT chapters track.
+pub fn webvtt(chapters: &[Chapter]) -> String {
+    let mut vtt = String::from("WEBVTT\n");
+    for (index, chapter) in chapters.iter().enumerate() {
+        vtt.push_str(&format!(
+            "\n{}\n{} --> {}\n{}\n",
+            index + 1,
+            vtt_timestamp(chapter.start),
+            vtt_timestamp(chapter.end),
+            escape_vtt(&chapter.title)
+        ));
+    }
+    vtt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chapter(title: &str, start: f64, end: f64) -> Chapter {
+        Chapter {
+            title: title.into(),
+            start,
+            end,
+            scene_id: None,
+            marker_ids: vec![],
+        }
+    }
+
+    #[test]
+    fn ffmetadata_uses_milliseconds() {
+        let chapters = [chapter("Intro", 0.0, 3.5), chapter("Scene", 3.5, 3723.0004)];
+        assert_eq!(
+            ffmetadata(&chapters),
+            ";FFMETADATA1\n\
+             \n[CHAPTER]\nTIMEBASE=1/1000\nSTART=0\nEND=3500\ntitle=Intro\n\
+             \n[CHAPTER]\nTIMEBASE=1/1000\nSTART=3500\nEND=3723000\ntitle=Scene\n"
+        );
+    }
+
+    #[test]
+    fn ffmetadata_escapes_titles() {
+        let metadata = ffmetadata(&[chapter("a=b; #1 \\ two\nlines", 0.0, 1.0)]);
+        assert!(metadata.contains("title=a\\=b\\; \\#1 \\\\ two\\\nlines\n"));
+    }
+
+    #[test]
+    fn vtt_timestamps() {
+        assert_eq!(vtt_timestamp(0.0), "00:00:00.000");
+        assert_eq!(vtt_timestamp(61.2345), "00:01:01.235");
+        assert_eq!(vtt_timestamp(3723.5), "01:02:03.500");
+    }
+
+    #[test]
+    fn webvtt_numbers_and_escapes_cues() {
+        let chapters = [
+            chapter("Intro", 0.0, 3.5),
+            chapter("Tom & Jerry <3\nagain", 3.5, 10.0),
+        ];
+        assert_eq!(
+            webvtt(&chapters),
+            "WEBVTT\n\
+             \n1\n00:00:00.000 --> 00:00:03.500\nIntro\n\
+             \n2\n00:00:03.500 --> 00:00:10.000\nTom &amp; Jerry &lt;3 again\n"
+        );
+    }
+}
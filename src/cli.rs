@@ -0,0 +1,671 @@
+use std::{io::Write, process::ExitCode, sync::Arc, time::Duration};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::{Args, Parser, Subcommand};
+
+use crate::{
+    audio::{BackgroundMusic, MusicMode},
+    beats::BeatSync,
+    chapters::ChapterMode,
+    clips::{ClipOrder, ClipStrategy, TargetDuration, Weighting},
+    config::Config,
+    error::AppError,
+    ffmpeg::{Transition, TransitionKind},
+    filters::{Criterion, Exclusions, MarkerFilter, Modifier, SceneFilter, TagCriterion},
+    http::{self, CreateVideoBody, FilterMode, Resolution},
+    jobs::{ErrorKind, JobError, JobStatus},
+    loudness::{LoudnessMode, LoudnessNormalization, LoudnessTarget},
+    overlays::{LowerThird, OverlayField, Overlays, TextPosition, TitleCard},
+    performers::Gender,
+    recipe::{self, Recipe, RecipeOverrides},
+    stash_api::{find_performers_query, find_tags_query, Api},
+    AppState, Result,
+};
+
+/// Exit codes of the headless commands. Invalid arguments exit with 2.
+mod exit_code {
+    pub const FAILED: u8 = 1;
+    pub const STASH_ERROR: u8 = 3;
+    pub const FFMPEG_ERROR: u8 = 4;
+    pub const NO_MARKERS: u8 = 5;
+    pub const CANCELLED: u8 = 130;
+}
+
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Start the web UI (the default if no command is given).
+    Serve,
+    /// Create a compilation without starting the web UI.
+    Compile(Box<CompileArgs>),
+    /// Render a compilation again from its saved recipe.
+    Rerun(RerunArgs),
+    /// Inspect or clean up the clip cache.
+    #[command(subcommand)]
+    Cache(CacheCommand),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CacheCommand {
+    /// Show how much space the cached clips take up.
+    Usage,
+    /// Remove all clips that aren't used by any video.
+    Purge,
+}
+
+impl Command {
+    pub fn is_headless(&self) -> bool {
+        !matches!(self, Command::Serve)
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct CompileArgs {
+    /// Comma-separated names or IDs of the tags to include markers for.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        required_unless_present_any = [
+            "performers",
+            "scene_tags",
+            "exclude_tags",
+            "exclude_scene_tags",
+            "exclude_performers",
+            "studios",
+            "min_rating",
+            "date_from",
+            "date_to",
+            "organized",
+            "min_o_counter",
+            "min_play_count",
+            "max_play_count",
+            "saved_filter",
+        ]
+    )]
+    pub tags: Vec<String>,
+    /// How markers are matched against `--tags`.
+    #[arg(long, value_enum, default_value = "includes")]
+    pub tags_modifier: Modifier,
+    /// Comma-separated names or IDs of the performers to include markers for.
+    #[arg(long, value_delimiter = ',')]
+    pub performers: Vec<String>,
+    /// How markers are matched against `--performers`.
+    #[arg(long, value_enum, default_value = "includes")]
+    pub performers_modifier: Modifier,
+    /// Comma-separated names or IDs of tags the markers' scenes must have.
+    #[arg(long, value_delimiter = ',')]
+    pub scene_tags: Vec<String>,
+    /// How scenes are matched against `--scene-tags`.
+    #[arg(long, value_enum, default_value = "includes")]
+    pub scene_tags_modifier: Modifier,
+    /// Also match child tags of the given tags, this many levels deep (-1 for all).
+    #[arg(long, allow_negative_numbers = true)]
+    pub tag_depth: Option<i64>,
+    /// Name or ID of a saved scene marker filter in Stash to take the markers from.
+    #[arg(long, conflicts_with_all = ["tags", "performers", "scene_tags"])]
+    pub saved_filter: Option<String>,
+    /// Comma-separated names or IDs of tags. Markers with any of them are left out.
+    #[arg(long, value_delimiter = ',')]
+    pub exclude_tags: Vec<String>,
+    /// Comma-separated names or IDs of tags. Markers from scenes with any of them are
+    /// left out.
+    #[arg(long, value_delimiter = ',')]
+    pub exclude_scene_tags: Vec<String>,
+    /// Comma-separated names or IDs of performers. Markers from scenes with any of them
+    /// are left out.
+    #[arg(long, value_delimiter = ',')]
+    pub exclude_performers: Vec<String>,
+    /// Comma-separated names or IDs of studios. Only markers from their scenes, or
+    /// scenes of their sub-studios, are used.
+    #[arg(long, value_delimiter = ',')]
+    pub studios: Vec<String>,
+    /// Lowest scene rating, from 0 to 100 (four stars are 80).
+    #[arg(long)]
+    pub min_rating: Option<i64>,
+    /// Earliest scene date, as YYYY-MM-DD.
+    #[arg(long)]
+    pub date_from: Option<String>,
+    /// Latest scene date, as YYYY-MM-DD.
+    #[arg(long)]
+    pub date_to: Option<String>,
+    /// Only use organized (true) or unorganized (false) scenes.
+    #[arg(long)]
+    pub organized: Option<bool>,
+    /// Lowest O-counter of the scenes.
+    #[arg(long)]
+    pub min_o_counter: Option<i64>,
+    /// Lowest play count of the scenes.
+    #[arg(long)]
+    pub min_play_count: Option<i64>,
+    /// Highest play count of the scenes.
+    #[arg(long)]
+    pub max_play_count: Option<i64>,
+    /// Maximum duration per clip, in seconds.
+    #[arg(long, default_value_t = 15)]
+    pub duration: u32,
+    /// Output resolution.
+    #[arg(long, value_enum, default_value = "720")]
+    pub resolution: Resolution,
+    /// Output frames per second.
+    #[arg(long, default_value_t = 30)]
+    pub fps: u32,
+    /// Order of the clips in the compilation.
+    #[arg(long, value_enum, default_value = "scene-order")]
+    pub order: ClipOrder,
+    /// Seed for random clip orders. Chosen randomly if not given.
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Comma-separated names or IDs of tags, in the order they should appear in
+    /// with `--order tag`.
+    #[arg(long, value_delimiter = ',')]
+    pub tag_order: Vec<String>,
+    /// How clips are taken from each marker: `random[:seed]`, `equal-slices`, `single`,
+    /// `evenly-spaced[:count]` or `whole-marker`.
+    #[arg(long, default_value = "random")]
+    pub strategy: ClipStrategy,
+    /// Total length of the compilation in seconds. Clips are shortened or dropped to fit.
+    #[arg(long)]
+    pub target_duration: Option<u32>,
+    /// How many seconds the compilation may be longer than the target duration, to keep
+    /// clips whole.
+    #[arg(long, default_value_t = 10, requires = "target_duration")]
+    pub tolerance: u32,
+    /// Give markers from higher rated scenes more time when using a target duration.
+    #[arg(long, requires = "target_duration")]
+    pub weight_by_rating: bool,
+    /// Transition between clips. Re-encodes the whole video, which takes longer.
+    #[arg(long, value_enum)]
+    pub transition: Option<TransitionKind>,
+    /// Length of the transitions, in seconds.
+    #[arg(long, default_value_t = 0.5, requires = "transition")]
+    pub transition_duration: f64,
+    /// Comma-separated audio files to play under the video, repeated until it ends.
+    #[arg(long, value_delimiter = ',')]
+    pub music: Vec<Utf8PathBuf>,
+    /// How the music is combined with the original audio.
+    #[arg(long, value_enum, default_value = "mix")]
+    pub music_mode: MusicMode,
+    /// Volume of the music, between 0 and 1.
+    #[arg(long, default_value_t = 0.5)]
+    pub music_volume: f64,
+    /// Cut to the beat of the music, changing clips every this many beats.
+    #[arg(long, requires = "music", conflicts_with = "transition")]
+    pub beats_per_clip: Option<u32>,
+    /// Text shown on a card before the first clip.
+    #[arg(long)]
+    pub intro: Option<String>,
+    /// Text shown on a card after the last clip.
+    #[arg(long)]
+    pub outro: Option<String>,
+    /// How long the intro and outro cards are shown, in seconds.
+    #[arg(long, default_value_t = 3.0)]
+    pub card_duration: f64,
+    /// Where the text goes on the intro and outro cards.
+    #[arg(long, value_enum, default_value = "center")]
+    pub card_position: TextPosition,
+    /// Font size of the cards in pixels. Defaults to a twelfth of the video's height.
+    #[arg(long)]
+    pub card_size: Option<u32>,
+    /// Comma-separated information shown at the start of every clip.
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub lower_third: Vec<OverlayField>,
+    /// How long the lower third is shown, in seconds.
+    #[arg(long, default_value_t = 4.0)]
+    pub lower_third_duration: f64,
+    /// Where the lower third goes.
+    #[arg(long, value_enum, default_value = "bottom-left")]
+    pub lower_third_position: TextPosition,
+    /// Font size of the lower third in pixels. Defaults to a twenty-fourth of the video's height.
+    #[arg(long)]
+    pub lower_third_size: Option<u32>,
+    /// Font file or font name for all text.
+    #[arg(long, default_value = "Sans")]
+    pub font: String,
+    /// What the chapters of the video cover.
+    #[arg(long, value_enum, default_value = "marker")]
+    pub chapters: ChapterMode,
+    /// Comma-separated genders of the performers named in overlays, chapters and the
+    /// markers file. Uses the configured genders if not given.
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub performer_genders: Vec<Gender>,
+    /// Normalize the loudness of every clip or of the finished video.
+    #[arg(long, value_enum)]
+    pub loudness: Option<LoudnessMode>,
+    /// Target integrated loudness, in LUFS.
+    #[arg(long, default_value_t = -16.0, requires = "loudness", allow_negative_numbers = true)]
+    pub target_lufs: f64,
+    /// Maximum true peak, in dBTP.
+    #[arg(long, default_value_t = -1.5, requires = "loudness", allow_negative_numbers = true)]
+    pub true_peak: f64,
+    /// Name of the encoding profile, e.g. `draft` or `archival`.
+    #[arg(long)]
+    pub profile: Option<String>,
+    /// Where to write the finished video. Defaults to `videos/<id>.mp4`.
+    #[arg(short, long)]
+    pub output: Option<Utf8PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct RerunArgs {
+    /// Path to the recipe, e.g. `videos/abcd1234.recipe.json`.
+    pub recipe: Utf8PathBuf,
+    /// Output resolution, overrides the one in the recipe.
+    #[arg(long)]
+    pub resolution: Option<Resolution>,
+    /// Output frames per second, overrides the ones in the recipe.
+    #[arg(long)]
+    pub fps: Option<u32>,
+    /// Clip order, overrides the one in the recipe.
+    #[arg(long)]
+    pub order: Option<ClipOrder>,
+    /// Seed for random clip orders, overrides the one in the recipe.
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Clip strategy, overrides the one in the recipe.
+    #[arg(long)]
+    pub strategy: Option<ClipStrategy>,
+    /// Encoding profile, overrides the one in the recipe.
+    #[arg(long)]
+    pub profile: Option<String>,
+    /// Where to write the finished video. Defaults to `videos/<id>.mp4`.
+    #[arg(short, long)]
+    pub output: Option<Utf8PathBuf>,
+}
+
+/// Runs one of the headless commands and returns the process' exit code.
+pub async fn run(state: Arc<AppState>, command: Command) -> ExitCode {
+    let result = match command {
+        Command::Serve => unreachable!("the web UI is not a headless command"),
+        Command::Compile(args) => compile(state, *args).await,
+        Command::Rerun(args) => rerun(state, args).await,
+        Command::Cache(command) => cache(state, command).await,
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {e}");
+            let error = JobError::from_error(&AppError::Generic(e));
+            exit_code_for(error.kind)
+        }
+    }
+}
+
+fn exit_code_for(kind: ErrorKind) -> ExitCode {
+    match kind {
+        ErrorKind::Stash => ExitCode::from(exit_code::STASH_ERROR),
+        ErrorKind::Ffmpeg => ExitCode::from(exit_code::FFMPEG_ERROR),
+        ErrorKind::Io | ErrorKind::Other => ExitCode::from(exit_code::FAILED),
+    }
+}
+
+/// Resolves names (case-insensitively) or IDs to IDs.
+fn resolve_ids<'a>(
+    names: &[String],
+    candidates: impl Iterator<Item = (&'a str, &'a str)> + Clone,
+    kind: &str,
+) -> Result<Vec<String>> {
+    names
+        .iter()
+        .map(|name| {
+            candidates
+                .clone()
+                .find(|(id, candidate)| *id == name || candidate.eq_ignore_ascii_case(name))
+                .map(|(id, _)| id.to_string())
+                .ok_or_else(|| format!("no {kind} named '{name}' found").into())
+        })
+        .collect()
+}
+
+/// The overlays requested on the command line, if any.
+fn overlays(args: &CompileArgs) -> Option<Overlays> {
+    let card = |text: &Option<String>| {
+        text.clone().map(|text| TitleCard {
+            text,
+            duration: args.card_duration,
+            position: args.card_position,
+            size: args.card_size,
+        })
+    };
+    let lower_third = (!args.lower_third.is_empty()).then(|| LowerThird {
+        fields: args.lower_third.clone(),
+        duration: args.lower_third_duration,
+        position: args.lower_third_position,
+        size: args.lower_third_size,
+    });
+    let overlays = Overlays {
+        font: args.font.clone(),
+        intro: card(&args.intro),
+        lower_third,
+        outro: card(&args.outro),
+    };
+    let any =
+        overlays.intro.is_some() || overlays.lower_third.is_some() || overlays.outro.is_some();
+    any.then_some(overlays)
+}
+
+async fn compile(state: Arc<AppState>, args: CompileArgs) -> Result<ExitCode> {
+    let api = Api::load_config().await?;
+    let performers = if args.performers.is_empty() && args.exclude_performers.is_empty() {
+        vec![]
+    } else {
+        api.find_performers(find_performers_query::Variables {
+            favorites: None,
+            tags: None,
+        })
+        .await?
+    };
+    let performer_candidates = || performers.iter().map(|p| (p.id.as_str(), p.name.as_str()));
+    let performer_ids = resolve_ids(&args.performers, performer_candidates(), "performer")?;
+
+    let tag_lists = [
+        &args.tags,
+        &args.scene_tags,
+        &args.exclude_tags,
+        &args.exclude_scene_tags,
+    ];
+    let tags = if tag_lists.iter().all(|l| l.is_empty()) {
+        vec![]
+    } else {
+        api.find_tags(find_tags_query::Variables {}).await?
+    };
+    let tag_candidates = || tags.iter().map(|t| (t.id.as_str(), t.name.as_str()));
+    let tag_ids = resolve_ids(&args.tags, tag_candidates(), "tag")?;
+    let scene_tag_ids = resolve_ids(&args.scene_tags, tag_candidates(), "tag")?;
+
+    let exclusions = Exclusions {
+        tags: resolve_ids(&args.exclude_tags, tag_candidates(), "tag")?,
+        scene_tags: resolve_ids(&args.exclude_scene_tags, tag_candidates(), "tag")?,
+        performers: resolve_ids(
+            &args.exclude_performers,
+            performer_candidates(),
+            "performer",
+        )?,
+    };
+    let exclusions_only = || {
+        (!exclusions.is_empty()).then(|| MarkerFilter {
+            exclusions: exclusions.clone(),
+            ..Default::default()
+        })
+    };
+
+    // a plain list of tags or performers is saved the way the web UI does it
+    let simple = args.scene_tags.is_empty()
+        && args.tag_depth.is_none()
+        && args.tags_modifier == Modifier::Includes
+        && args.performers_modifier == Modifier::Includes;
+    let saved_filter = match &args.saved_filter {
+        Some(name) => {
+            let filters = api.find_saved_filters().await?;
+            let candidates = filters.iter().map(|f| (f.id.as_str(), f.name.as_str()));
+            resolve_ids(std::slice::from_ref(name), candidates, "saved filter")?
+        }
+        None => vec![],
+    };
+    let (select_mode, selected_ids, filter) = match (simple, args.tags.is_empty()) {
+        _ if !saved_filter.is_empty() => (FilterMode::SavedFilter, saved_filter, exclusions_only()),
+        (true, true) if !args.performers.is_empty() => {
+            (FilterMode::Performers, performer_ids, exclusions_only())
+        }
+        (true, false) if args.performers.is_empty() => {
+            (FilterMode::Tags, tag_ids, exclusions_only())
+        }
+        _ => {
+            let tag_criterion = |ids: Vec<String>, modifier| {
+                (!ids.is_empty()).then_some(TagCriterion {
+                    ids,
+                    modifier,
+                    depth: args.tag_depth,
+                })
+            };
+            let filter = MarkerFilter {
+                performers: (!performer_ids.is_empty()).then_some(Criterion {
+                    ids: performer_ids,
+                    modifier: args.performers_modifier,
+                }),
+                tags: tag_criterion(tag_ids, args.tags_modifier),
+                scene_tags: tag_criterion(scene_tag_ids, args.scene_tags_modifier),
+                exclusions: exclusions.clone(),
+                saved_filter: None,
+            };
+            (FilterMode::Combined, vec![], Some(filter))
+        }
+    };
+
+    let tag_order = if args.tag_order.is_empty() {
+        vec![]
+    } else {
+        let tags = api.find_tags(find_tags_query::Variables {}).await?;
+        let candidates = tags.iter().map(|t| (t.id.as_str(), t.name.as_str()));
+        resolve_ids(&args.tag_order, candidates, "tag")?
+    };
+
+    let studios = if args.studios.is_empty() {
+        vec![]
+    } else {
+        api.find_studios().await?
+    };
+    let studio_candidates = studios.iter().map(|s| (s.id.as_str(), s.name.as_str()));
+    let scene_filter = SceneFilter {
+        studios: resolve_ids(&args.studios, studio_candidates, "studio")?,
+        min_rating: args.min_rating,
+        date_from: args.date_from.clone(),
+        date_to: args.date_to.clone(),
+        organized: args.organized,
+        min_o_counter: args.min_o_counter,
+        min_play_count: args.min_play_count,
+        max_play_count: args.max_play_count,
+    };
+    scene_filter.validate()?;
+    let scene_filter = (!scene_filter.is_empty()).then_some(scene_filter);
+
+    let filter_to_query = match &filter {
+        Some(filter) => filter.clone(),
+        None => MarkerFilter::from_selection(select_mode, selected_ids.clone()),
+    };
+    let markers = http::query_markers(&api, &filter_to_query, scene_filter.as_ref()).await?;
+    if markers.is_empty() {
+        eprintln!("no markers found for the given filter");
+        return Ok(ExitCode::from(exit_code::NO_MARKERS));
+    }
+    eprintln!("found {} markers", markers.len());
+
+    let overlays = overlays(&args);
+    let options = CreateVideoBody {
+        select_mode,
+        selected_ids,
+        filter,
+        scene_filter,
+        clip_order: args.order,
+        order_seed: args.seed,
+        tag_order,
+        clip_duration: args.duration,
+        clip_strategy: args.strategy,
+        target_duration: args.target_duration.map(|duration| TargetDuration {
+            duration,
+            tolerance: args.tolerance,
+            weighting: if args.weight_by_rating {
+                Weighting::SceneRating
+            } else {
+                Weighting::Equal
+            },
+        }),
+        output_resolution: args.resolution,
+        output_fps: args.fps,
+        selected_markers: markers.iter().map(|m| m.id.clone()).collect(),
+        markers,
+        id: recipe::generate_id(),
+        transition: args.transition.map(|kind| Transition {
+            kind,
+            duration: args.transition_duration,
+        }),
+        music: (!args.music.is_empty()).then_some(BackgroundMusic {
+            files: args.music,
+            mode: args.music_mode,
+            volume: args.music_volume,
+        }),
+        beat_sync: args
+            .beats_per_clip
+            .map(|beats_per_clip| BeatSync { beats_per_clip }),
+        overlays,
+        chapters: args.chapters,
+        performer_genders: (!args.performer_genders.is_empty())
+            .then(|| args.performer_genders.clone()),
+        loudness: args.loudness.map(|mode| LoudnessNormalization {
+            mode,
+            target: LoudnessTarget {
+                integrated: args.target_lufs,
+                true_peak: args.true_peak,
+            },
+        }),
+        encoding_profile: args.profile,
+        marker_windows: Default::default(),
+    };
+    run_job(state, options, args.output.as_deref()).await
+}
+
+async fn rerun(state: Arc<AppState>, args: RerunArgs) -> Result<ExitCode> {
+    let recipe = Recipe::load(&args.recipe).await?;
+    let overrides = RecipeOverrides {
+        output_resolution: args.resolution,
+        output_fps: args.fps,
+        clip_order: args.order,
+        order_seed: args.seed,
+        clip_strategy: args.strategy,
+        encoding_profile: args.profile,
+    };
+    let api = Api::load_config().await?;
+    let options = recipe.to_options(&api, &overrides).await?;
+    if options.markers.is_empty() {
+        eprintln!("none of the markers in the recipe exist anymore");
+        return Ok(ExitCode::from(exit_code::NO_MARKERS));
+    }
+    eprintln!(
+        "re-rendering video {} with {} markers",
+        recipe.options.id,
+        options.markers.len()
+    );
+
+    run_job(state, options, args.output.as_deref()).await
+}
+
+async fn cache(state: Arc<AppState>, command: CacheCommand) -> Result<ExitCode> {
+    const MEGABYTE: u64 = 1024 * 1024;
+    let cache = &state.ffmpeg.cache;
+    match command {
+        CacheCommand::Usage => {
+            let max_size = Config::get()
+                .await
+                .ok()
+                .and_then(|c| c.max_clip_cache_size_mb);
+            let usage = cache.usage(max_size)?;
+            println!(
+                "{} clips, {} MB in {}",
+                usage.clip_count,
+                usage.total_size / MEGABYTE,
+                cache.dir()
+            );
+            if let Some(max_size) = usage.max_size {
+                println!("limit: {} MB", max_size / MEGABYTE);
+            }
+            for video in usage.videos {
+                println!(
+                    "{}: {} clips, {} MB",
+                    video.id,
+                    video.clip_count,
+                    video.clip_size / MEGABYTE
+                );
+            }
+        }
+        CacheCommand::Purge => {
+            // the server may be rendering with clips from the same cache
+            if state.database.has_active_videos()? {
+                eprintln!(
+                    "videos are queued or being rendered, purge the cache once they are done"
+                );
+                return Ok(ExitCode::from(exit_code::FAILED));
+            }
+            let result = cache.purge(&state.ffmpeg.video_dir).await?;
+            println!(
+                "removed {} files, freed {} MB",
+                result.removed_files,
+                result.freed_size / MEGABYTE
+            );
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Queues the video, prints its progress until it is done and moves the finished
+/// video to `output`, if given. Pressing Ctrl-C cancels the job.
+async fn run_job(
+    state: Arc<AppState>,
+    options: CreateVideoBody,
+    output: Option<&Utf8Path>,
+) -> Result<ExitCode> {
+    let id = options.id.clone();
+    http::submit_video(state.clone(), options).await?;
+
+    let mut interval = tokio::time::interval(Duration::from_millis(500));
+    let job = loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = tokio::signal::ctrl_c() => {
+                eprintln!("\ncancelling job {id}...");
+                state.jobs.cancel(&id).await.ok();
+            }
+        }
+
+        let job = state.jobs.get(&id).await.ok_or("job disappeared")?;
+        if job.status.is_done() {
+            eprintln!();
+            break job;
+        }
+        eprint!(
+            "\r{} / {} clips finished",
+            job.progress.finished, job.progress.total
+        );
+        std::io::stderr().flush()?;
+    };
+
+    match job.status {
+        JobStatus::Finished => {
+            let mut path = state.ffmpeg.video_dir.join(format!("{id}.mp4"));
+            if let Some(output) = output {
+                move_file(&path, output).await?;
+                let size = tokio::fs::metadata(output).await?.len();
+                state.database.set_output(&id, output, size)?;
+                path = output.to_owned();
+            }
+            println!("{path}");
+            Ok(ExitCode::SUCCESS)
+        }
+        JobStatus::Cancelled => {
+            eprintln!("job {id} was cancelled");
+            Ok(ExitCode::from(exit_code::CANCELLED))
+        }
+        _ => {
+            let error = job.error.ok_or("job failed without an error")?;
+            eprintln!("creating the video failed: {}", error.message);
+            Ok(exit_code_for(error.kind))
+        }
+    }
+}
+
+async fn move_file(from: &Utf8Path, to: &Utf8Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    // renaming fails across file systems, so fall back to copying.
+    if tokio::fs::rename(from, to).await.is_err() {
+        tokio::fs::copy(from, to).await?;
+        tokio::fs::remove_file(from).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,239 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::UNIX_EPOCH,
+};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Serialize;
+use tokio::sync::OwnedMutexGuard;
+
+use crate::{database::Database, ffmpeg::ClipParameters, Result};
+
+const MEGABYTE: u64 = 1024 * 1024;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VideoCacheUsage {
+    pub id: String,
+    pub clip_count: u64,
+    /// Size of all clips used by the video. Clips can be shared between videos.
+    pub clip_size: u64,
+    pub output_size: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheUsage {
+    pub clip_count: u64,
+    pub total_size: u64,
+    pub max_size: Option<u64>,
+    pub videos: Vec<VideoCacheUsage>,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PurgeResult {
+    pub removed_files: u64,
+    pub freed_size: u64,
+}
+
+type ClipLocks = Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>;
+
+/// Encoded clips, stored in a directory and tracked in the database.
+#[derive(Clone)]
+pub struct ClipCache {
+    dir: Utf8PathBuf,
+    database: Arc<Database>,
+    /// Clips that are being encoded right now, shared by all jobs.
+    encoding: ClipLocks,
+}
+
+/// Held while a clip is encoded, so no other job encodes the same file at the same time.
+pub struct ClipLock {
+    file_name: String,
+    locks: ClipLocks,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for ClipLock {
+    fn drop(&mut self) {
+        let mut locks = self.locks.lock().unwrap();
+        self.guard.take();
+        // nobody else is waiting for the clip if only the map still holds its lock
+        if locks
+            .get(&self.file_name)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.file_name);
+        }
+    }
+}
+
+impl ClipCache {
+    pub fn new(dir: Utf8PathBuf, database: Arc<Database>) -> Self {
+        ClipCache {
+            dir,
+            database,
+            encoding: Default::default(),
+        }
+    }
+
+    pub fn dir(&self) -> &Utf8Path {
+        &self.dir
+    }
+
+    pub fn path(&self, parameters: &ClipParameters) -> Utf8PathBuf {
+        self.dir.join(parameters.file_name())
+    }
+
+    /// Checks whether a clip with exactly these parameters has been encoded before.
+    pub fn contains(&self, parameters: &ClipParameters) -> Result<bool> {
+        if !self.path(parameters).is_file() {
+            return Ok(false);
+        }
+        let cached = self.database.get_clip(&parameters.file_name())?;
+        Ok(cached.as_ref() == Some(parameters))
+    }
+
+    /// Waits until no other job is encoding the clip and takes its lock.
+    pub async fn lock(&self, parameters: &ClipParameters) -> ClipLock {
+        let file_name = parameters.file_name();
+        let lock = self
+            .encoding
+            .lock()
+            .unwrap()
+            .entry(file_name.clone())
+            .or_default()
+            .clone();
+        ClipLock {
+            file_name,
+            locks: self.encoding.clone(),
+            guard: Some(lock.lock_owned().await),
+        }
+    }
+
+    /// Records a newly encoded clip in the cache's manifest.
+    pub async fn insert(&self, parameters: &ClipParameters) -> Result<()> {
+        let file_size = tokio::fs::metadata(self.path(parameters)).await?.len();
+        self.database
+            .insert_clip(&parameters.file_name(), parameters, file_size)
+    }
+
+    /// Marks a clip as used by the given video.
+    pub fn use_clip(&self, video_id: &str, parameters: &ClipParameters) -> Result<()> {
+        let file_name = parameters.file_name();
+        self.database.add_video_clip(video_id, &file_name)?;
+        self.database.touch_clip(&file_name)
+    }
+
+    pub fn usage(&self, max_size_mb: Option<u64>) -> Result<CacheUsage> {
+        let (clip_count, total_size) = self.database.clip_totals()?;
+        Ok(CacheUsage {
+            clip_count,
+            total_size,
+            max_size: max_size_mb.map(|mb| mb * MEGABYTE),
+            videos: self.database.clip_usage_per_video()?,
+        })
+    }
+
+    async fn remove(&self, file_name: &str) -> Result<()> {
+        let path = self.dir.join(file_name);
+        if path.is_file() {
+            tokio::fs::remove_file(&path).await?;
+        }
+        self.database.delete_clip(file_name)
+    }
+
+    /// Removes the least recently used clips until the cache is smaller than the limit.
+    /// Clips used by queued or running jobs are never removed.
+    pub async fn evict(&self, max_size_mb: u64) -> Result<PurgeResult> {
+        let max_size = max_size_mb * MEGABYTE;
+        let (_, mut total_size) = self.database.clip_totals()?;
+        let mut result = PurgeResult::default();
+        if total_size <= max_size {
+            return Ok(result);
+        }
+
+        for (file_name, size) in self.database.evictable_clips()? {
+            if total_size <= max_size {
+                break;
+            }
+            tracing::debug!("evicting clip {file_name}");
+            self.remove(&file_name).await?;
+            total_size = total_size.saturating_sub(size);
+            result.removed_files += 1;
+            result.freed_size += size;
+        }
+
+        tracing::info!(
+            "evicted {} clips ({} MB) from the clip cache",
+            result.removed_files,
+            result.freed_size / MEGABYTE
+        );
+        Ok(result)
+    }
+
+    /// Removes all clips that aren't used by any video in the database, as well as
+    /// files that aren't tracked at all, like clips from older versions. Untracked clips
+    /// that changed after the newest clip was added are kept, because another process
+    /// may still be encoding them.
+    pub async fn purge(&self, video_dir: &Utf8Path) -> Result<PurgeResult> {
+        lazy_static! {
+            static ref LEGACY_CLIP_REGEX: Regex = Regex::new(r#"^\d+_\d+-\d+\.mp4$"#).unwrap();
+        }
+
+        let mut result = PurgeResult::default();
+        for (file_name, size) in self.database.unreferenced_clips()? {
+            self.remove(&file_name).await?;
+            result.removed_files += 1;
+            result.freed_size += size;
+        }
+
+        let newest_clip = self.database.newest_clip_time()?.unwrap_or(0);
+        let mut stray_files = vec![];
+        if self.dir.is_dir() {
+            for entry in self.dir.read_dir_utf8()? {
+                let entry = entry?;
+                if self.database.get_clip(entry.file_name())?.is_some() {
+                    continue;
+                }
+                let modified = entry
+                    .metadata()?
+                    .modified()?
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs() as i64);
+                if modified > newest_clip {
+                    tracing::info!("keeping {}, it may still be encoding", entry.path());
+                } else {
+                    stray_files.push(entry.into_path());
+                }
+            }
+        }
+        if video_dir.is_dir() {
+            for entry in video_dir.read_dir_utf8()? {
+                let entry = entry?;
+                let name = entry.file_name();
+                if LEGACY_CLIP_REGEX.is_match(name) || name == "clips.txt" {
+                    stray_files.push(entry.into_path());
+                }
+            }
+        }
+        for path in stray_files {
+            if path.is_file() {
+                result.freed_size += tokio::fs::metadata(&path).await?.len();
+                tokio::fs::remove_file(&path).await?;
+                result.removed_files += 1;
+            }
+        }
+
+        tracing::info!(
+            "purged {} files ({} MB) from the clip cache",
+            result.removed_files,
+            result.freed_size / MEGABYTE
+        );
+        Ok(result)
+    }
+}
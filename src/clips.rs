@@ -0,0 +1,643 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+};
+
+use camino::Utf8PathBuf;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::stash_api::find_markers_query::FindMarkersQueryFindSceneMarkersSceneMarkers as Marker;
+
+/// The seed used for random choices if none is given.
+pub const DEFAULT_SEED: u64 = 123456789;
+
+/// Shortest clip that is created, in seconds.
+const MIN_CLIP_DURATION: u32 = 2;
+
+/// How the clips for a marker are chosen from its time range.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum ClipStrategy {
+    /// Clips of half, a third or a quarter of the clip duration, chosen randomly.
+    Random {
+        #[serde(default = "default_seed")]
+        seed: u64,
+    },
+    /// Consecutive clips of the full clip duration.
+    EqualSlices,
+    /// One clip from the start of the marker.
+    Single,
+    /// `count` clips spread evenly across the marker.
+    EvenlySpaced {
+        #[serde(default = "default_clip_count")]
+        count: u32,
+    },
+    /// The marker from start to end as one clip.
+    WholeMarker,
+}
+
+fn default_seed() -> u64 {
+    DEFAULT_SEED
+}
+
+fn default_clip_count() -> u32 {
+    3
+}
+
+impl Default for ClipStrategy {
+    fn default() -> Self {
+        ClipStrategy::Random { seed: DEFAULT_SEED }
+    }
+}
+
+impl ClipStrategy {
+    /// Computes `(start, duration)` pairs for a marker spanning `start..end`. If the end
+    /// is unknown, the marker is assumed to be `clip_duration` seconds long.
+    pub fn clip_offsets(
+        &self,
+        start: u32,
+        end: Option<u32>,
+        clip_duration: u32,
+    ) -> Vec<(u32, u32)> {
+        let clip_duration = clip_duration.max(MIN_CLIP_DURATION);
+        let end = end.unwrap_or(start + clip_duration);
+        let length = end.saturating_sub(start);
+
+        match *self {
+            ClipStrategy::Random { seed } => {
+                let clip_lengths = [
+                    (clip_duration / 2).max(MIN_CLIP_DURATION),
+                    (clip_duration / 3).max(MIN_CLIP_DURATION),
+                    (clip_duration / 4).max(MIN_CLIP_DURATION),
+                ];
+                let mut rng = StdRng::seed_from_u64(seed);
+                let mut offset = start;
+                let mut offsets = vec![];
+                while offset < end {
+                    let duration = clip_lengths.choose(&mut rng).unwrap();
+                    offsets.push((offset, *duration));
+                    offset += duration;
+                }
+                offsets
+            }
+            ClipStrategy::EqualSlices => {
+                let mut offsets = vec![];
+                let mut offset = start;
+                while offset + MIN_CLIP_DURATION <= end {
+                    let duration = clip_duration.min(end - offset);
+                    offsets.push((offset, duration));
+                    offset += duration;
+                }
+                offsets
+            }
+            ClipStrategy::Single => vec![(start, clip_duration.min(length.max(MIN_CLIP_DURATION)))],
+            ClipStrategy::EvenlySpaced { count } => {
+                let duration = clip_duration.min(length.max(MIN_CLIP_DURATION));
+                let count = count.max(1);
+                let free = length.saturating_sub(duration);
+                if count == 1 || free == 0 {
+                    return vec![(start, duration)];
+                }
+                (0..count)
+                    .map(|i| (start + free * i / (count - 1), duration))
+                    .collect()
+            }
+            ClipStrategy::WholeMarker => vec![(start, length.max(MIN_CLIP_DURATION))],
+        }
+    }
+}
+
+impl FromStr for ClipStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, argument) = match s.split_once(':') {
+            Some((name, argument)) => (name, Some(argument)),
+            None => (s, None),
+        };
+        let parse_argument = |default| match argument {
+            Some(a) => a
+                .parse()
+                .map_err(|_| format!("invalid number '{a}' for clip strategy {name}")),
+            None => Ok(default),
+        };
+
+        match name {
+            "random" => Ok(ClipStrategy::Random {
+                seed: parse_argument(DEFAULT_SEED)?,
+            }),
+            "equal-slices" => Ok(ClipStrategy::EqualSlices),
+            "single" => Ok(ClipStrategy::Single),
+            "evenly-spaced" => Ok(ClipStrategy::EvenlySpaced {
+                count: parse_argument(default_clip_count() as u64)? as u32,
+            }),
+            "whole-marker" => Ok(ClipStrategy::WholeMarker),
+            _ => Err(format!(
+                "unknown clip strategy '{name}', expected one of random[:seed], equal-slices, single, evenly-spaced[:count], whole-marker"
+            )),
+        }
+    }
+}
+
+/// Asks for a compilation of a certain length instead of using every clip.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TargetDuration {
+    /// Total length of the compilation, in seconds.
+    pub duration: u32,
+    /// How many seconds the result may be longer than the target, so clips can be kept
+    /// whole instead of being cut short.
+    #[serde(default = "default_tolerance")]
+    pub tolerance: u32,
+    #[serde(default)]
+    pub weighting: Weighting,
+}
+
+fn default_tolerance() -> u32 {
+    10
+}
+
+/// How the target duration is divided between markers.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum Weighting {
+    /// Every marker gets the same amount of time.
+    #[default]
+    Equal,
+    /// By the scene's rating, unrated scenes count as 50 out of 100.
+    SceneRating,
+    /// By the marker's primary tag, with weights by tag ID. Other tags have a weight of 1.
+    Tags { weights: HashMap<String, f64> },
+    /// By the highest weight of the scene's performers, by performer ID. Other performers
+    /// have a weight of 1.
+    Performers { weights: HashMap<String, f64> },
+}
+
+impl Weighting {
+    fn weight(&self, marker: &Marker) -> f64 {
+        let weight = match self {
+            Weighting::Equal => 1.0,
+            Weighting::SceneRating => marker.scene.rating100.unwrap_or(50) as f64,
+            Weighting::Tags { weights } => *weights.get(&marker.primary_tag.id).unwrap_or(&1.0),
+            Weighting::Performers { weights } => marker
+                .scene
+                .performers
+                .iter()
+                .map(|p| *weights.get(&p.id).unwrap_or(&1.0))
+                .reduce(f64::max)
+                .unwrap_or(1.0),
+        };
+        weight.max(0.0)
+    }
+}
+
+impl TargetDuration {
+    /// Shortens or drops clips so their total length is close to the target. Every marker
+    /// gets a share of the time according to its weight. Time a marker can't use because
+    /// it is too short is given to the others. A clip that doesn't fit into the share is
+    /// kept whole as long as the total stays within the tolerance, and shortened after.
+    pub fn plan(&self, markers: &mut [(&Marker, Vec<(u32, u32)>)]) {
+        let available: Vec<f64> = markers
+            .iter()
+            .map(|(_, offsets)| offsets.iter().map(|(_, d)| *d as f64).sum())
+            .collect();
+        let weights: Vec<f64> = markers
+            .iter()
+            .map(|(m, _)| self.weighting.weight(m))
+            .collect();
+
+        // hand out the budget by weight, repeating whenever a marker can't use its whole share.
+        let mut shares = vec![0.0; markers.len()];
+        let mut open: Vec<usize> = (0..markers.len()).filter(|i| weights[*i] > 0.0).collect();
+        let mut budget = self.duration as f64;
+        loop {
+            let total_weight: f64 = open.iter().map(|i| weights[*i]).sum();
+            if open.is_empty() || total_weight == 0.0 {
+                break;
+            }
+            let (saturated, rest): (Vec<usize>, Vec<usize>) = open
+                .iter()
+                .partition(|i| available[**i] <= budget * weights[**i] / total_weight);
+            if saturated.is_empty() {
+                for i in &rest {
+                    shares[*i] = budget * weights[*i] / total_weight;
+                }
+                break;
+            }
+            for i in saturated {
+                shares[i] = available[i];
+                budget -= available[i];
+            }
+            open = rest;
+        }
+
+        // time that doesn't fit into a marker's clips is carried over to the next one.
+        let mut carry = 0.0;
+        let mut total = 0;
+        let mut slack = self.tolerance;
+        for ((_, offsets), share) in markers.iter_mut().zip(shares) {
+            let mut remaining = share + carry;
+            let mut planned = vec![];
+            for &(start, duration) in offsets.iter() {
+                let left = remaining.round() as u32;
+                if left < MIN_CLIP_DURATION {
+                    break;
+                }
+                let duration = if duration > left && duration - left <= slack {
+                    slack -= duration - left;
+                    duration
+                } else {
+                    duration.min(left)
+                };
+                planned.push((start, duration));
+                remaining -= duration as f64;
+            }
+            carry = remaining.max(0.0);
+            total += planned.iter().map(|(_, d)| d).sum::<u32>();
+            *offsets = planned;
+        }
+
+        if total.abs_diff(self.duration) > self.tolerance {
+            tracing::warn!(
+                "planned {total} seconds of clips, which is not within {} seconds of the target of {} seconds",
+                self.tolerance,
+                self.duration
+            );
+        } else {
+            tracing::info!(
+                "planned {total} seconds of clips for a target of {} seconds",
+                self.duration
+            );
+        }
+    }
+}
+
+/// An encoded clip and the marker it was taken from.
+#[derive(Debug)]
+pub struct Clip<'a> {
+    pub path: Utf8PathBuf,
+    pub marker: &'a Marker,
+    pub start: u32,
+    pub duration: u32,
+}
+
+impl Clip<'_> {
+    fn scene_id(&self) -> u64 {
+        self.marker.scene.id.parse().unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClipOrder {
+    /// Shuffled.
+    Random,
+    /// Clips from different scenes interspersed with each other.
+    SceneOrder,
+    /// Grouped by the marker's primary tag, in the order given by `tagOrder`.
+    Tag,
+    /// Grouped by the scene's performers.
+    Performer,
+    /// Oldest scenes first.
+    SceneDate,
+    /// Highest rated scenes first.
+    Rating,
+    /// Most played scenes first.
+    PlayCount,
+    /// Shuffled, but never two clips from the same scene in a row if that can be avoided.
+    NoConsecutiveScenes,
+}
+
+/// Settings for ordering clips, besides the order itself.
+#[derive(Debug, Default)]
+pub struct OrderOptions<'a> {
+    pub seed: u64,
+    /// Tag IDs in the order the tags should appear in. Other tags come afterwards.
+    pub tag_order: &'a [String],
+}
+
+/// Puts the clips in the given order. Clips are expected in the order of their markers,
+/// which is kept for clips that are equal according to the order.
+pub fn order_clips<'a>(
+    mut clips: Vec<Clip<'a>>,
+    order: ClipOrder,
+    options: &OrderOptions,
+) -> Vec<Clip<'a>> {
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    match order {
+        ClipOrder::Random => {
+            clips.shuffle(&mut rng);
+            clips
+        }
+        ClipOrder::SceneOrder => intersperse_scene_clips(clips, &mut rng),
+        ClipOrder::Tag => {
+            let position = |clip: &Clip| {
+                let tag = &clip.marker.primary_tag;
+                let index = options.tag_order.iter().position(|id| id == &tag.id);
+                (index.unwrap_or(usize::MAX), tag.name.clone())
+            };
+            clips.sort_by_cached_key(position);
+            clips
+        }
+        ClipOrder::Performer => {
+            clips.sort_by_cached_key(|clip| {
+                let mut names: Vec<_> = clip
+                    .marker
+                    .scene
+                    .performers
+                    .iter()
+                    .map(|p| p.name.to_lowercase())
+                    .collect();
+                names.sort();
+                // scenes without performers go last
+                (names.is_empty(), names)
+            });
+            clips
+        }
+        ClipOrder::SceneDate => {
+            clips.sort_by_cached_key(|clip| {
+                let date = clip.marker.scene.date.clone();
+                (date.is_none(), date, clip.scene_id())
+            });
+            clips
+        }
+        ClipOrder::Rating => {
+            clips.sort_by_key(|clip| (Reverse(clip.marker.scene.rating100), clip.scene_id()));
+            clips
+        }
+        ClipOrder::PlayCount => {
+            clips.sort_by_key(|clip| (Reverse(clip.marker.scene.play_count), clip.scene_id()));
+            clips
+        }
+        ClipOrder::NoConsecutiveScenes => shuffle_without_repeats(clips, &mut rng),
+    }
+}
+
+fn intersperse_scene_clips<'a>(mut clips: Vec<Clip<'a>>, rng: &mut StdRng) -> Vec<Clip<'a>> {
+    use itertools::Itertools;
+
+    clips.sort_by_key(|c| c.scene_id());
+
+    let iter = clips.into_iter().group_by(|c| c.scene_id());
+    let mut clips = vec![];
+    for (_, group) in &iter {
+        for (idx, clip) in group.enumerate() {
+            let rand = rng.gen::<u32>();
+            clips.push((idx, rand, clip));
+        }
+    }
+
+    clips.sort_by_key(|(idx, rand, _)| Reverse((*idx, *rand)));
+    clips.into_iter().map(|(_, _, c)| c).collect()
+}
+
+/// Shuffles the clips of every scene, then always picks the next clip from the scene with
+/// the most clips left, other than the previous one. This only fails to avoid repeats if
+/// one scene has more than half of all clips.
+fn shuffle_without_repeats<'a>(clips: Vec<Clip<'a>>, rng: &mut StdRng) -> Vec<Clip<'a>> {
+    let total = clips.len();
+    let mut scenes: BTreeMap<u64, Vec<Clip>> = BTreeMap::new();
+    for clip in clips {
+        scenes.entry(clip.scene_id()).or_default().push(clip);
+    }
+    for clips in scenes.values_mut() {
+        clips.shuffle(rng);
+    }
+
+    let mut result = Vec::with_capacity(total);
+    let mut previous = None;
+    while result.len() < total {
+        let others = || {
+            scenes
+                .iter()
+                .filter(|(id, clips)| Some(**id) != previous && !clips.is_empty())
+        };
+        let most_left = others().map(|(_, clips)| clips.len()).max();
+        let candidates: Vec<u64> = others()
+            .filter(|(_, clips)| Some(clips.len()) == most_left)
+            .map(|(id, _)| *id)
+            .collect();
+        let scene_id = match candidates.choose(rng) {
+            Some(id) => *id,
+            None => {
+                tracing::info!("could not avoid consecutive clips from the same scene");
+                previous.expect("there must be clips left")
+            }
+        };
+        let clip = scenes.get_mut(&scene_id).unwrap().pop().unwrap();
+        result.push(clip);
+        previous = Some(scene_id);
+    }
+    result
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    pub(crate) fn marker(id: &str, rating: Option<i64>) -> Marker {
+        serde_json::from_value(json!({
+            "id": id,
+            "seconds": 0.0,
+            "end_seconds": null,
+            "stream": "",
+            "screenshot": "",
+            "primary_tag": {"id": "1", "name": "Tag"},
+            "tags": [],
+            "scene": {
+                "id": id,
+                "title": null,
+                "date": null,
+                "rating100": rating,
+                "play_count": null,
+                "studio": null,
+                "tags": [],
+                "performers": [],
+                "files": [],
+                "sceneStreams": [],
+                "scene_markers": [],
+            },
+        }))
+        .unwrap()
+    }
+
+    fn total(markers: &[(&Marker, Vec<(u32, u32)>)]) -> u32 {
+        markers
+            .iter()
+            .flat_map(|(_, offsets)| offsets.iter().map(|(_, d)| d))
+            .sum()
+    }
+
+    #[test]
+    fn random_offsets_are_reproducible() {
+        let strategy = ClipStrategy::Random { seed: 42 };
+        let first = strategy.clip_offsets(30, Some(90), 12);
+        let second = strategy.clip_offsets(30, Some(90), 12);
+        assert_eq!(first, second);
+
+        let other_seed = ClipStrategy::Random { seed: 43 }.clip_offsets(30, Some(90), 12);
+        assert_ne!(first, other_seed);
+    }
+
+    #[test]
+    fn random_offsets_are_consecutive() {
+        let offsets = ClipStrategy::Random { seed: 1 }.clip_offsets(30, Some(90), 12);
+        assert_eq!(offsets[0].0, 30);
+        for pair in offsets.windows(2) {
+            assert_eq!(pair[0].0 + pair[0].1, pair[1].0);
+        }
+        let (last_start, _) = offsets.last().unwrap();
+        assert!(*last_start < 90);
+        assert!(offsets.iter().all(|(_, d)| [6, 4, 3].contains(d)));
+    }
+
+    #[test]
+    fn equal_slices_stay_within_the_marker() {
+        let strategy = ClipStrategy::EqualSlices;
+        assert_eq!(
+            strategy.clip_offsets(10, Some(35), 10),
+            vec![(10, 10), (20, 10), (30, 5)]
+        );
+        // a rest shorter than the minimum clip duration is dropped
+        assert_eq!(
+            strategy.clip_offsets(0, Some(21), 10),
+            vec![(0, 10), (10, 10)]
+        );
+        assert_eq!(strategy.clip_offsets(0, None, 10), vec![(0, 10)]);
+    }
+
+    #[test]
+    fn single_and_whole_marker() {
+        assert_eq!(
+            ClipStrategy::Single.clip_offsets(5, Some(8), 10),
+            vec![(5, 3)]
+        );
+        assert_eq!(
+            ClipStrategy::Single.clip_offsets(5, None, 10),
+            vec![(5, 10)]
+        );
+        assert_eq!(
+            ClipStrategy::WholeMarker.clip_offsets(10, Some(70), 10),
+            vec![(10, 60)]
+        );
+        assert_eq!(
+            ClipStrategy::WholeMarker.clip_offsets(10, Some(11), 10),
+            vec![(10, MIN_CLIP_DURATION)]
+        );
+    }
+
+    #[test]
+    fn evenly_spaced_offsets() {
+        let strategy = ClipStrategy::EvenlySpaced { count: 3 };
+        assert_eq!(
+            strategy.clip_offsets(0, Some(100), 10),
+            vec![(0, 10), (45, 10), (90, 10)]
+        );
+        // no room to spread the clips
+        assert_eq!(strategy.clip_offsets(0, Some(8), 10), vec![(0, 8)]);
+    }
+
+    #[test]
+    fn parse_strategies() {
+        assert_eq!(
+            "random:7".parse::<ClipStrategy>(),
+            Ok(ClipStrategy::Random { seed: 7 })
+        );
+        assert_eq!(
+            "evenly-spaced".parse::<ClipStrategy>(),
+            Ok(ClipStrategy::EvenlySpaced { count: 3 })
+        );
+        assert!("random:x".parse::<ClipStrategy>().is_err());
+        assert!("slices".parse::<ClipStrategy>().is_err());
+    }
+
+    #[test]
+    fn plan_shortens_to_the_target() {
+        let (a, b) = (marker("1", None), marker("2", None));
+        let mut markers = vec![
+            (&a, vec![(0, 10), (10, 10), (20, 10)]),
+            (&b, vec![(0, 10), (10, 10), (20, 10)]),
+        ];
+        let target = TargetDuration {
+            duration: 30,
+            tolerance: 0,
+            weighting: Weighting::Equal,
+        };
+        target.plan(&mut markers);
+        assert_eq!(markers[0].1, vec![(0, 10), (10, 5)]);
+        assert_eq!(markers[1].1, vec![(0, 10), (10, 5)]);
+        assert_eq!(total(&markers), 30);
+    }
+
+    #[test]
+    fn plan_gives_unused_time_to_other_markers() {
+        let (short, long) = (marker("1", None), marker("2", None));
+        let mut markers = vec![
+            (&short, vec![(0, 4)]),
+            (&long, vec![(0, 10), (10, 10), (20, 10)]),
+        ];
+        let target = TargetDuration {
+            duration: 20,
+            tolerance: 0,
+            weighting: Weighting::Equal,
+        };
+        target.plan(&mut markers);
+        assert_eq!(markers[0].1, vec![(0, 4)]);
+        assert_eq!(markers[1].1, vec![(0, 10), (10, 6)]);
+    }
+
+    #[test]
+    fn plan_weights_by_rating() {
+        let (good, bad) = (marker("1", Some(75)), marker("2", Some(25)));
+        let mut markers = vec![
+            (&good, vec![(0, 10), (10, 10), (20, 10)]),
+            (&bad, vec![(0, 10), (10, 10), (20, 10)]),
+        ];
+        let target = TargetDuration {
+            duration: 20,
+            tolerance: 0,
+            weighting: Weighting::SceneRating,
+        };
+        target.plan(&mut markers);
+        assert_eq!(markers[0].1, vec![(0, 10), (10, 5)]);
+        assert_eq!(markers[1].1, vec![(0, 5)]);
+    }
+
+    #[test]
+    fn plan_keeps_clips_whole_within_the_tolerance() {
+        let (a, b) = (marker("1", None), marker("2", None));
+        let mut markers = vec![
+            (&a, vec![(0, 10), (10, 10), (20, 10)]),
+            (&b, vec![(0, 10), (10, 10), (20, 10)]),
+        ];
+        let target = TargetDuration {
+            duration: 30,
+            tolerance: 7,
+            weighting: Weighting::Equal,
+        };
+        target.plan(&mut markers);
+        // the first marker uses 5 of the 7 seconds, too few are left for the second one
+        assert_eq!(markers[0].1, vec![(0, 10), (10, 10)]);
+        assert_eq!(markers[1].1, vec![(0, 10), (10, 5)]);
+        assert_eq!(total(&markers), 35);
+    }
+
+    #[test]
+    fn plan_drops_clips_below_the_minimum() {
+        let (a, b) = (marker("1", None), marker("2", None));
+        let mut markers = vec![(&a, vec![(0, 10)]), (&b, vec![(0, 10)])];
+        let target = TargetDuration {
+            duration: 2,
+            tolerance: 0,
+            weighting: Weighting::Equal,
+        };
+        target.plan(&mut markers);
+        // one second each is too short, so it is carried over to the second marker
+        assert!(markers[0].1.is_empty());
+        assert_eq!(markers[1].1, vec![(0, 2)]);
+    }
+}
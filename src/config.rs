@@ -1,4 +1,4 @@
-use crate::Result;
+use crate::{encoding::EncodingProfile, performers::Gender, Result};
 use camino::{Utf8Path, Utf8PathBuf};
 use directories::ProjectDirs;
 use lazy_static::lazy_static;
@@ -14,6 +14,33 @@ lazy_static! {
 pub struct Config {
     pub stash_url: String,
     pub api_key: String,
+    #[serde(default = "default_max_concurrent_jobs")]
+    pub max_concurrent_jobs: usize,
+    /// Maximum size of the clip cache in megabytes. Unlimited if not set.
+    #[serde(default)]
+    pub max_clip_cache_size_mb: Option<u64>,
+    /// How many clips are encoded in parallel. Defaults to a value based on the number of cores.
+    #[serde(default)]
+    pub encoding_workers: Option<usize>,
+    /// Additional encoding profiles, or replacements for the built-in ones.
+    #[serde(default)]
+    pub encoding_profiles: Vec<EncodingProfile>,
+    /// Genders of the performers listed in the web UI and named in scene labels, unless
+    /// a request chooses others. All genders if empty.
+    #[serde(default)]
+    pub performer_genders: Vec<Gender>,
+}
+
+/// The subset of the configuration that is entered in the web UI.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StashSettings {
+    pub stash_url: String,
+    pub api_key: String,
+}
+
+fn default_max_concurrent_jobs() -> usize {
+    1
 }
 
 impl Config {
@@ -53,6 +80,26 @@ pub async fn init() {
     }
 }
 
+pub async fn set_stash_settings(settings: StashSettings) -> Result<()> {
+    let config = match Config::get().await {
+        Ok(config) => Config {
+            stash_url: settings.stash_url,
+            api_key: settings.api_key,
+            ..config
+        },
+        Err(_) => Config {
+            stash_url: settings.stash_url,
+            api_key: settings.api_key,
+            max_concurrent_jobs: default_max_concurrent_jobs(),
+            max_clip_cache_size_mb: None,
+            encoding_workers: None,
+            encoding_profiles: vec![],
+            performer_genders: vec![],
+        },
+    };
+    set_config(config).await
+}
+
 pub async fn set_config(config: Config) -> Result<()> {
     use tokio::fs;
 
@@ -0,0 +1,417 @@
+use std::{
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use camino::Utf8Path;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    clip_cache::VideoCacheUsage,
+    ffmpeg::ClipParameters,
+    http::{stored_options, CreateVideoBody},
+    jobs::{JobError, JobStatus},
+    Result,
+};
+
+/// Schema migrations, applied in order. The index of the last applied migration is
+/// stored in the database's `user_version`.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE videos (
+    id TEXT PRIMARY KEY NOT NULL,
+    status TEXT NOT NULL,
+    options TEXT NOT NULL,
+    queued_at INTEGER NOT NULL,
+    started_at INTEGER,
+    finished_at INTEGER,
+    output_path TEXT,
+    file_size INTEGER,
+    error TEXT
+)",
+    "CREATE TABLE clips (
+    file_name TEXT PRIMARY KEY NOT NULL,
+    scene_id TEXT NOT NULL,
+    parameters TEXT NOT NULL,
+    file_size INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    last_used_at INTEGER NOT NULL
+)",
+    "CREATE TABLE video_clips (
+    video_id TEXT NOT NULL,
+    file_name TEXT NOT NULL,
+    PRIMARY KEY (video_id, file_name)
+)",
+];
+
+/// A compilation that was created (or attempted) at some point.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VideoRecord {
+    pub id: String,
+    pub status: JobStatus,
+    pub options: CreateVideoBody,
+    pub queued_at: i64,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+    pub output_path: Option<String>,
+    pub file_size: Option<u64>,
+    pub error: Option<JobError>,
+}
+
+impl VideoRecord {
+    fn from_row(row: &Row) -> Result<Self> {
+        let status: String = row.get(1)?;
+        let options: String = row.get(2)?;
+        let error: Option<String> = row.get(8)?;
+        Ok(VideoRecord {
+            id: row.get(0)?,
+            status: status.parse().unwrap_or(JobStatus::Failed),
+            options: stored_options::deserialize(serde_json::from_str::<Value>(&options)?)?,
+            queued_at: row.get(3)?,
+            started_at: row.get(4)?,
+            finished_at: row.get(5)?,
+            output_path: row.get(6)?,
+            file_size: row.get(7)?,
+            error: error.and_then(|e| serde_json::from_str(&e).ok()),
+        })
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time before unix epoch")
+        .as_secs() as i64
+}
+
+pub struct Database {
+    connection: Mutex<Connection>,
+}
+
+impl Database {
+    pub fn new() -> Result<Self> {
+        Self::open(Utf8Path::new("compilations.sqlite"))
+    }
+
+    pub fn open(path: &Utf8Path) -> Result<Self> {
+        tracing::info!("opening database at {path}");
+        let mut connection = Connection::open(path)?;
+        migrate(&mut connection)?;
+
+        Ok(Database {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Marks the videos that were still queued or running as failed, since jobs don't
+    /// survive a restart. Only the server does this when it starts: the CLI opens the
+    /// same database, and doing it there would fail the jobs of a running server.
+    pub fn fail_interrupted_videos(&self) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        let interrupted = connection.execute(
+            "UPDATE videos SET status = ?1 WHERE status IN (?2, ?3)",
+            params![
+                JobStatus::Failed.as_str(),
+                JobStatus::Queued.as_str(),
+                JobStatus::Running.as_str()
+            ],
+        )?;
+        if interrupted > 0 {
+            tracing::info!("marked {interrupted} interrupted job(s) as failed");
+        }
+        Ok(())
+    }
+
+    /// Whether any video is queued or running, in this process or another one using the
+    /// same database.
+    pub fn has_active_videos(&self) -> Result<bool> {
+        let connection = self.connection.lock().unwrap();
+        let count: i64 = connection.query_row(
+            "SELECT COUNT(*) FROM videos WHERE status IN (?1, ?2)",
+            [JobStatus::Queued.as_str(), JobStatus::Running.as_str()],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
+    pub fn insert_video(&self, options: &CreateVideoBody) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute("DELETE FROM video_clips WHERE video_id = ?1", [&options.id])?;
+        connection.execute(
+            "INSERT OR REPLACE INTO videos (id, status, options, queued_at) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                options.id,
+                JobStatus::Queued.as_str(),
+                stored_options::serialize(options, serde_json::value::Serializer)?.to_string(),
+                now()
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_status(&self, id: &str, status: JobStatus, error: Option<&JobError>) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        let error = error.map(serde_json::to_string).transpose()?;
+        if status == JobStatus::Running {
+            connection.execute(
+                "UPDATE videos SET status = ?2, started_at = ?3 WHERE id = ?1",
+                params![id, status.as_str(), now()],
+            )?;
+        } else {
+            connection.execute(
+                "UPDATE videos SET status = ?2, finished_at = ?3, error = ?4 WHERE id = ?1",
+                params![id, status.as_str(), now(), error],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn set_output(&self, id: &str, path: &Utf8Path, file_size: u64) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "UPDATE videos SET output_path = ?2, file_size = ?3 WHERE id = ?1",
+            params![id, path.as_str(), file_size],
+        )?;
+        Ok(())
+    }
+
+    pub fn list_videos(&self) -> Result<Vec<VideoRecord>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT id, status, options, queued_at, started_at, finished_at, output_path, file_size, error
+             FROM videos ORDER BY queued_at DESC",
+        )?;
+        let mut rows = statement.query([])?;
+        let mut videos = vec![];
+        while let Some(row) = rows.next()? {
+            // one unreadable video shouldn't hide all the others
+            match VideoRecord::from_row(row) {
+                Ok(video) => videos.push(video),
+                Err(e) => {
+                    let id: String = row.get(0)?;
+                    tracing::warn!("skipping video {id}, its record can't be read: {e}");
+                }
+            }
+        }
+        Ok(videos)
+    }
+
+    /// Records a newly encoded clip in the manifest of the clip cache.
+    pub fn insert_clip(
+        &self,
+        file_name: &str,
+        parameters: &ClipParameters,
+        file_size: u64,
+    ) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        let now = now();
+        connection.execute(
+            "INSERT OR REPLACE INTO clips (file_name, scene_id, parameters, file_size, created_at, last_used_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?5)",
+            params![
+                file_name,
+                parameters.scene_id,
+                serde_json::to_string(parameters)?,
+                file_size,
+                now
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_clip(&self, file_name: &str) -> Result<Option<ClipParameters>> {
+        let connection = self.connection.lock().unwrap();
+        let parameters: Option<String> = connection
+            .query_row(
+                "SELECT parameters FROM clips WHERE file_name = ?1",
+                [file_name],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match parameters {
+            Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn touch_clip(&self, file_name: &str) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "UPDATE clips SET last_used_at = ?2 WHERE file_name = ?1",
+            params![file_name, now()],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_clip(&self, file_name: &str) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute("DELETE FROM clips WHERE file_name = ?1", [file_name])?;
+        connection.execute("DELETE FROM video_clips WHERE file_name = ?1", [file_name])?;
+        Ok(())
+    }
+
+    pub fn add_video_clip(&self, video_id: &str, file_name: &str) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT OR IGNORE INTO video_clips (video_id, file_name) VALUES (?1, ?2)",
+            [video_id, file_name],
+        )?;
+        Ok(())
+    }
+
+    /// When the most recent clip was added to the manifest, in seconds since the epoch.
+    pub fn newest_clip_time(&self) -> Result<Option<i64>> {
+        let connection = self.connection.lock().unwrap();
+        let time =
+            connection.query_row("SELECT MAX(created_at) FROM clips", [], |row| row.get(0))?;
+        Ok(time)
+    }
+
+    /// Returns the number of cached clips and their total size.
+    pub fn clip_totals(&self) -> Result<(u64, u64)> {
+        let connection = self.connection.lock().unwrap();
+        let totals = connection.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(file_size), 0) FROM clips",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        Ok(totals)
+    }
+
+    pub fn clip_usage_per_video(&self) -> Result<Vec<VideoCacheUsage>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT v.id, COUNT(c.file_name), COALESCE(SUM(c.file_size), 0), v.file_size
+             FROM videos v
+             LEFT JOIN video_clips vc ON vc.video_id = v.id
+             LEFT JOIN clips c ON c.file_name = vc.file_name
+             GROUP BY v.id
+             ORDER BY v.queued_at DESC",
+        )?;
+        let usage = statement
+            .query_map([], |row| {
+                Ok(VideoCacheUsage {
+                    id: row.get(0)?,
+                    clip_count: row.get(1)?,
+                    clip_size: row.get(2)?,
+                    output_size: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(usage)
+    }
+
+    /// Returns the clips that aren't used by queued or running jobs, least recently used first.
+    pub fn evictable_clips(&self) -> Result<Vec<(String, u64)>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT file_name, file_size FROM clips WHERE file_name NOT IN (
+                SELECT vc.file_name FROM video_clips vc
+                JOIN videos v ON v.id = vc.video_id
+                WHERE v.status IN (?1, ?2)
+             )
+             ORDER BY last_used_at ASC",
+        )?;
+        let clips = statement
+            .query_map(
+                [JobStatus::Queued.as_str(), JobStatus::Running.as_str()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(clips)
+    }
+
+    /// Returns the clips that aren't used by any video.
+    pub fn unreferenced_clips(&self) -> Result<Vec<(String, u64)>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT file_name, file_size FROM clips WHERE file_name NOT IN (
+                SELECT vc.file_name FROM video_clips vc JOIN videos v ON v.id = vc.video_id
+             )",
+        )?;
+        let clips = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(clips)
+    }
+}
+
+fn migrate(connection: &mut Connection) -> Result<()> {
+    let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version >= MIGRATIONS.len() {
+        return Ok(());
+    }
+
+    let transaction = connection.transaction()?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        tracing::info!("applying database migration {}", index + 1);
+        transaction.execute_batch(migration)?;
+    }
+    transaction.pragma_update(None, "user_version", MIGRATIONS.len())?;
+    transaction.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn options(id: &str) -> Value {
+        json!({
+            "selectMode": "tags",
+            "selectedIds": ["1"],
+            "clipOrder": "random",
+            "clipDuration": 10,
+            "outputResolution": "720",
+            "outputFps": 30,
+            "selectedMarkers": ["5"],
+            "markers": [],
+            "id": id,
+        })
+    }
+
+    fn insert_raw(database: &Database, id: &str, options: &str) {
+        let connection = database.connection.lock().unwrap();
+        connection
+            .execute(
+                "INSERT INTO videos (id, status, options, queued_at) VALUES (?1, ?2, ?3, ?4)",
+                params![id, JobStatus::Finished.as_str(), options, 1],
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn stores_options_without_markers() {
+        let database = Database::open(Utf8Path::new(":memory:")).unwrap();
+        let options: CreateVideoBody = serde_json::from_value(options("new")).unwrap();
+        database.insert_video(&options).unwrap();
+
+        let connection = database.connection.lock().unwrap();
+        let stored: String = connection
+            .query_row("SELECT options FROM videos", [], |row| row.get(0))
+            .unwrap();
+        let stored: Value = serde_json::from_str(&stored).unwrap();
+        assert!(stored.get("markers").is_none());
+        assert_eq!(stored["selectedMarkers"], json!(["5"]));
+    }
+
+    #[test]
+    fn lists_old_videos_and_skips_unreadable_ones() {
+        let database = Database::open(Utf8Path::new(":memory:")).unwrap();
+        // markers from an older version, missing fields that are required now
+        let mut old = options("old");
+        old["markers"] = json!([{"id": "5", "seconds": 12.0}]);
+        insert_raw(&database, "old", &old.to_string());
+        insert_raw(&database, "broken", r#"{"id": "broken"}"#);
+
+        let videos = database.list_videos().unwrap();
+        assert_eq!(videos.len(), 1);
+        assert_eq!(videos[0].id, "old");
+        assert!(videos[0].options.markers.is_empty());
+        assert_eq!(videos[0].options.selected_markers, vec!["5"]);
+    }
+}
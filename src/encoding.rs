@@ -0,0 +1,200 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Config, Result};
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Codec settings used to encode clips.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClipEncoding {
+    pub video_codec: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crf: Option<u32>,
+    /// Target video bitrate, e.g. `2M`. Can be combined with `crf` for codecs that
+    /// use it as an upper bound, like VP9.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub video_bitrate: Option<String>,
+    pub audio_codec: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_bitrate: Option<String>,
+    pub audio_sample_rate: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pixel_format: Option<String>,
+}
+
+impl Default for ClipEncoding {
+    fn default() -> Self {
+        ClipEncoding {
+            video_codec: "libx264".into(),
+            preset: Some("slow".into()),
+            crf: Some(22),
+            video_bitrate: None,
+            audio_codec: "aac".into(),
+            audio_bitrate: None,
+            audio_sample_rate: 48000,
+            pixel_format: None,
+        }
+    }
+}
+
+impl ClipEncoding {
+    /// The ffmpeg output arguments for these settings.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec!["-c:v".to_string(), self.video_codec.clone()];
+        if let Some(preset) = &self.preset {
+            args.extend(["-preset".into(), preset.clone()]);
+        }
+        if let Some(crf) = self.crf {
+            args.extend(["-crf".into(), crf.to_string()]);
+        }
+        if let Some(bitrate) = &self.video_bitrate {
+            args.extend(["-b:v".into(), bitrate.clone()]);
+        }
+        if let Some(pixel_format) = &self.pixel_format {
+            args.extend(["-pix_fmt".into(), pixel_format.clone()]);
+        }
+        args.extend(self.audio_args());
+        args
+    }
+
+    /// The ffmpeg output arguments for the audio stream only.
+    pub fn audio_args(&self) -> Vec<String> {
+        let mut args = vec!["-c:a".to_string(), self.audio_codec.clone()];
+        if let Some(bitrate) = &self.audio_bitrate {
+            args.extend(["-b:a".into(), bitrate.clone()]);
+        }
+        args.extend(["-ar".into(), self.audio_sample_rate.to_string()]);
+        args
+    }
+
+    /// Checks that the ffmpeg build supports the configured codecs.
+    pub fn validate(&self, encoders: &HashSet<String>) -> Result<()> {
+        for codec in [&self.video_codec, &self.audio_codec] {
+            if !encoders.contains(codec) {
+                return Err(format!("ffmpeg does not support the encoder '{codec}'").into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A named set of encoding settings that can be chosen when creating a video.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EncodingProfile {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub encoding: ClipEncoding,
+}
+
+impl EncodingProfile {
+    fn new(name: &str, description: &str, encoding: ClipEncoding) -> Self {
+        EncodingProfile {
+            name: name.into(),
+            description: description.into(),
+            encoding,
+        }
+    }
+}
+
+fn builtin_profiles() -> Vec<EncodingProfile> {
+    vec![
+        EncodingProfile::new(
+            DEFAULT_PROFILE,
+            "H.264, good quality",
+            ClipEncoding::default(),
+        ),
+        EncodingProfile::new(
+            "draft",
+            "H.264, fast to encode, for previewing",
+            ClipEncoding {
+                preset: Some("veryfast".into()),
+                crf: Some(28),
+                audio_bitrate: Some("128k".into()),
+                ..Default::default()
+            },
+        ),
+        EncodingProfile::new(
+            "archival",
+            "H.265, high quality",
+            ClipEncoding {
+                video_codec: "libx265".into(),
+                preset: Some("slow".into()),
+                crf: Some(18),
+                audio_bitrate: Some("256k".into()),
+                pixel_format: Some("yuv420p10le".into()),
+                ..Default::default()
+            },
+        ),
+        EncodingProfile::new(
+            "phone",
+            "H.264, small files that play everywhere",
+            ClipEncoding {
+                preset: Some("medium".into()),
+                crf: Some(26),
+                audio_bitrate: Some("96k".into()),
+                pixel_format: Some("yuv420p".into()),
+                ..Default::default()
+            },
+        ),
+        EncodingProfile::new(
+            "vp9",
+            "VP9 and Opus",
+            ClipEncoding {
+                video_codec: "libvpx-vp9".into(),
+                preset: None,
+                crf: Some(31),
+                video_bitrate: Some("0".into()),
+                audio_codec: "libopus".into(),
+                audio_bitrate: Some("128k".into()),
+                ..Default::default()
+            },
+        ),
+        EncodingProfile::new(
+            "av1",
+            "AV1 (SVT-AV1) and Opus",
+            ClipEncoding {
+                video_codec: "libsvtav1".into(),
+                preset: Some("8".into()),
+                crf: Some(30),
+                audio_codec: "libopus".into(),
+                audio_bitrate: Some("128k".into()),
+                ..Default::default()
+            },
+        ),
+    ]
+}
+
+/// All available profiles. Profiles from the configuration replace built-in
+/// ones with the same name.
+pub async fn profiles() -> Vec<EncodingProfile> {
+    let mut profiles = builtin_profiles();
+    let configured = Config::get()
+        .await
+        .map(|c| c.encoding_profiles)
+        .unwrap_or_default();
+    for profile in configured {
+        match profiles.iter_mut().find(|p| p.name == profile.name) {
+            Some(existing) => *existing = profile,
+            None => profiles.push(profile),
+        }
+    }
+    profiles
+}
+
+/// Parses the output of `ffmpeg -encoders` into the names of the encoders.
+pub fn parse_encoders(output: &str) -> HashSet<String> {
+    output
+        .lines()
+        .skip_while(|line| !line.trim_start().starts_with("---"))
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(String::from)
+        .collect()
+}
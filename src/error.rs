@@ -1,4 +1,4 @@
-use std::io;
+use std::{fmt, io};
 
 use axum::{
     response::{IntoResponse, Response},
@@ -7,12 +7,16 @@ use axum::{
 use reqwest::StatusCode;
 use serde_json::json;
 
-type StdError = Box<dyn std::error::Error>;
+type StdError = Box<dyn std::error::Error + Send + Sync>;
 
 #[derive(Debug)]
 pub enum AppError {
     Generic(StdError),
     Io(io::Error),
+    NotFound(String),
+    Conflict(String),
+    /// The request is invalid, like options that can't be combined.
+    BadRequest(String),
 }
 
 impl From<StdError> for AppError {
@@ -27,18 +31,35 @@ impl From<io::Error> for AppError {
     }
 }
 
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Generic(e) => write!(f, "{e}"),
+            AppError::Io(e) => write!(f, "io error: {e}"),
+            AppError::NotFound(e) | AppError::Conflict(e) | AppError::BadRequest(e) => {
+                write!(f, "{e}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
         tracing::error!("request failed: {:?}", self);
-        let error_message = match self {
-            AppError::Generic(e) => e.to_string(),
-            AppError::Io(e) => format!("io error: {e}"),
+        let status = match self {
+            AppError::Generic(_) | AppError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
         };
+        let error_message = self.to_string();
 
         let body = Json(json!({
             "error": error_message,
         }));
 
-        (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
+        (status, body).into_response()
     }
 }
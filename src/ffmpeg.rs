@@ -1,36 +1,115 @@
-use std::{cmp::Reverse, process::Output};
+use std::{
+    collections::HashSet,
+    fmt,
+    process::{Output, Stdio},
+    sync::Arc,
+};
 
 use camino::{Utf8Path, Utf8PathBuf};
-use futures::lock::Mutex;
+use futures::stream::{self, StreamExt, TryStreamExt};
 use lazy_static::lazy_static;
-use rand::{rngs::StdRng, seq::SliceRandom, RngCore, SeedableRng};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use tokio::process::Command;
+use sha2::{Digest, Sha256};
+use tokio::{process::Command, sync::OnceCell};
 
 use crate::{
+    audio::{self, BackgroundMusic},
+    beats::{self, BeatGrid, BeatSync},
+    chapters::{self, Chapter},
+    clip_cache::ClipCache,
+    clips::{self, Clip, ClipStrategy, OrderOptions},
+    config::Config,
+    database::Database,
     download_ffmpeg,
-    http::CreateVideoBody,
-    stash_api::find_markers_query::{
-        FindMarkersQueryFindSceneMarkersSceneMarkers as Marker, GenderEnum,
-    },
+    encoding::{self, ClipEncoding},
+    http::{CreateVideoBody, MarkerWindow},
+    jobs::JobHandle,
+    loudness::{self, LoudnessMeasurement, LoudnessMode, LoudnessTarget},
+    overlays::{Overlays, TextOverlay},
+    performers::{self, Gender},
+    stash_api::find_markers_query::FindMarkersQueryFindSceneMarkersSceneMarkers as Marker,
     Result,
 };
 
-#[derive(Debug, Default, Clone, Serialize)]
-pub struct Progress {
-    pub finished: usize,
-    pub total: usize,
-}
+/// Number of threads each ffmpeg process uses for encoding a clip.
+const FFMPEG_THREADS: usize = 4;
 
-lazy_static! {
-    static ref PROGRESS: Mutex<Progress> = Default::default();
+/// How many clips are encoded at the same time. Defaults to one ffmpeg process
+/// per `FFMPEG_THREADS` cores.
+async fn encoding_workers() -> usize {
+    let configured = Config::get().await.ok().and_then(|c| c.encoding_workers);
+    configured
+        .unwrap_or_else(|| {
+            let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+            cores / FFMPEG_THREADS
+        })
+        .max(1)
 }
 
 #[derive(Clone)]
 pub struct Ffmpeg {
     path: Utf8PathBuf,
     pub video_dir: Utf8PathBuf,
+    pub cache: ClipCache,
+    encoders: Arc<OnceCell<HashSet<String>>>,
+}
+
+/// Everything that determines the contents of an encoded clip. Clips are only
+/// reused from the cache if all of these match.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClipParameters {
+    pub scene_id: String,
+    /// Fingerprint of the scene's file, so re-encoded or replaced files aren't mixed up.
+    pub source_fingerprint: String,
+    pub start: u32,
+    pub duration: u32,
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+    pub encoding: ClipEncoding,
+    /// Set if the clip's audio is normalized on its own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loudness: Option<LoudnessTarget>,
+    /// Text drawn onto the start of the clip.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overlay: Option<TextOverlay>,
+}
+
+impl ClipParameters {
+    fn cache_key(&self) -> String {
+        let json = serde_json::to_string(self).expect("clip parameters must be serializable");
+        let digest = format!("{:x}", Sha256::digest(json.as_bytes()));
+        digest[..16].to_string()
+    }
+
+    pub fn file_name(&self) -> String {
+        format!(
+            "{}_{}-{}_{}.mp4",
+            self.scene_id,
+            self.start,
+            self.start + self.duration,
+            self.cache_key()
+        )
+    }
+}
+
+/// Returns the oshash or MD5 checksum of the scene's file, whichever is available.
+pub fn source_fingerprint(marker: &Marker) -> String {
+    const TYPE_PRIORITIES: &[&str] = &["oshash", "md5"];
+
+    let fingerprints = marker
+        .scene
+        .files
+        .first()
+        .map(|f| f.fingerprints.as_slice())
+        .unwrap_or_default();
+    TYPE_PRIORITIES
+        .iter()
+        .find_map(|ty| fingerprints.iter().find(|f| &f.type_ == ty))
+        .map(|f| format!("{}:{}", f.type_, f.value))
+        .unwrap_or_else(|| format!("scene:{}", marker.scene.id))
 }
 
 pub fn find_stream_url(marker: &Marker) -> &str {
@@ -55,25 +134,28 @@ pub fn find_stream_url(marker: &Marker) -> &str {
     &streams[0].url
 }
 
-pub fn formatted_scene(marker: &Marker) -> String {
-    let female_performers: Vec<_> = marker
+/// The scene's title, or its file name if it has none.
+pub fn scene_title(marker: &Marker) -> &str {
+    match &marker.scene.title {
+        Some(title) if title.trim().len() > 0 => title,
+        _ => &marker.scene.files[0].basename,
+    }
+}
+
+/// Names of the scene's performers with one of the given genders.
+pub fn performer_names<'a>(marker: &'a Marker, genders: &[Gender]) -> Vec<&'a str> {
+    marker
         .scene
         .performers
         .iter()
-        .filter(|p| {
-            matches!(
-                &p.gender.as_ref().unwrap_or(&GenderEnum::FEMALE),
-                GenderEnum::FEMALE
-            )
-        })
+        .filter(|p| performers::shows_gender(genders, p.gender.as_ref().and_then(Into::into)))
         .map(|p| p.name.as_str())
-        .collect();
+        .collect()
+}
 
-    let title = match &marker.scene.title {
-        Some(title) if title.trim().len() > 0 => title,
-        _ => &marker.scene.files[0].basename,
-    };
-    let performers = female_performers.join(",");
+pub fn formatted_scene(marker: &Marker, genders: &[Gender]) -> String {
+    let title = scene_title(marker);
+    let performers = performer_names(marker, genders).join(",");
     let performers = match performers.as_str() {
         "" => "<no performers found>",
         _ => &performers,
@@ -81,144 +163,349 @@ pub fn formatted_scene(marker: &Marker) -> String {
     format!("'{}' ({})", title, performers)
 }
 
-fn commandline_error<T>(output: Output) -> Result<T> {
-    let stdout = std::str::from_utf8(&output.stdout).unwrap();
-    let stderr = std::str::from_utf8(&output.stderr).unwrap();
-    Err(format!(
-        "ffmpeg failed with nonzero exit code, stdout:\n{}\nstderr:\n{}",
-        stdout, stderr
-    )
-    .into())
+/// Moves the chapters behind the intro card and adds chapters for the cards.
+fn add_card_chapters(chapters: &mut Vec<Chapter>, overlays: &Overlays) {
+    let card_chapter = |text: &str, start: f64, end: f64| Chapter {
+        title: text.to_string(),
+        start,
+        end,
+        scene_id: None,
+        marker_ids: vec![],
+    };
+    let intro_duration = overlays.intro_duration();
+    for chapter in chapters.iter_mut() {
+        chapter.start += intro_duration;
+        chapter.end += intro_duration;
+    }
+    if let Some(outro) = &overlays.outro {
+        let start = chapters.last().map_or(intro_duration, |c| c.end);
+        chapters.push(card_chapter(&outro.text, start, start + outro.duration));
+    }
+    if let Some(intro) = &overlays.intro {
+        chapters.insert(0, card_chapter(&intro.text, 0.0, intro.duration));
+    }
 }
 
-pub async fn get_progress() -> Progress {
-    PROGRESS.lock().await.clone()
+/// How clips blend into each other when the video is re-encoded.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum TransitionKind {
+    Crossfade,
+    FadeThroughBlack,
+    Wipe,
+    DipToWhite,
 }
 
-#[derive(Clone, Copy, Debug, Deserialize)]
-#[serde(rename_all = "kebab-case")]
-pub enum ClipOrder {
-    Random,
-    SceneOrder,
+impl TransitionKind {
+    fn xfade_name(&self) -> &'static str {
+        match self {
+            TransitionKind::Crossfade => "fade",
+            TransitionKind::FadeThroughBlack => "fadeblack",
+            TransitionKind::Wipe => "wipeleft",
+            TransitionKind::DipToWhite => "fadewhite",
+        }
+    }
 }
 
-fn create_seeded_rng() -> StdRng {
-    StdRng::seed_from_u64(123456789)
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Transition {
+    pub kind: TransitionKind,
+    /// Length of each transition, in seconds.
+    #[serde(default = "default_transition_duration")]
+    pub duration: f64,
 }
 
-#[derive(Debug)]
-struct Clip {
-    scene_id: u32,
-    path: Utf8PathBuf,
+fn default_transition_duration() -> f64 {
+    0.5
 }
 
-impl Clip {
-    fn from_path(path: Utf8PathBuf) -> Self {
-        lazy_static! {
-            static ref FILE_REGEX: Regex = Regex::new(r#"(\d+)_(\d+)-(\d+)\.mp4"#).unwrap();
+impl Transition {
+    /// Checks that the transitions have a length and fit into the shortest clip, in
+    /// seconds.
+    pub fn validate(&self, shortest_clip: f64) -> Result<()> {
+        if self.duration <= 0.0 {
+            return Err("the transition duration must be greater than zero".into());
+        }
+        if self.duration > shortest_clip {
+            return Err(format!(
+                "the transition duration of {}s is longer than the shortest clip ({shortest_clip}s)",
+                self.duration
+            )
+            .into());
         }
+        Ok(())
+    }
+}
 
-        let filename = path.file_name().expect("path must have file name");
-        let matches = FILE_REGEX.captures(filename);
-        let (scene_id, _) = match matches {
-            Some(matches) => {
-                let scene_id = matches.get(1).unwrap().as_str();
-                let scene_id: u32 = scene_id.parse().unwrap();
+/// Builds a filter graph that chains the inputs with transitions. The outputs are
+/// labelled `[v]` and `[a]`.
+fn transition_filter(durations: &[f64], kind: TransitionKind, duration: f64) -> String {
+    let transition = kind.xfade_name();
+    let mut filters = vec![];
+    let mut video = "0:v".to_string();
+    let mut audio = "0:a".to_string();
+    let mut offset = 0.0;
+    for i in 1..durations.len() {
+        // every transition overlaps the previous clip by its duration
+        offset += durations[i - 1] - duration;
+        let (next_video, next_audio) = if i == durations.len() - 1 {
+            ("v".to_string(), "a".to_string())
+        } else {
+            (format!("v{i}"), format!("a{i}"))
+        };
+        filters.push(format!(
+            "[{video}][{i}:v]xfade=transition={transition}:duration={duration:.3}:offset={offset:.3}[{next_video}]"
+        ));
+        filters.push(format!(
+            "[{audio}][{i}:a]acrossfade=d={duration:.3}[{next_audio}]"
+        ));
+        video = next_video;
+        audio = next_audio;
+    }
+    filters.join(";\n")
+}
 
-                let start = matches.get(2).unwrap().as_str();
-                let start: u32 = start.parse().unwrap();
+/// Returned when ffmpeg exits with a nonzero exit code.
+#[derive(Debug)]
+pub struct CommandError {
+    pub stdout: String,
+    pub stderr: String,
+}
 
-                (scene_id, start)
-            }
-            None => (0, 0),
-        };
-        Clip { path, scene_id }
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ffmpeg failed with nonzero exit code, stdout:\n{}\nstderr:\n{}",
+            self.stdout, self.stderr
+        )
     }
 }
 
-fn intersperse_scene_clips(clips: Vec<Utf8PathBuf>) -> Vec<Utf8PathBuf> {
-    use itertools::Itertools;
+impl std::error::Error for CommandError {}
+
+/// Wraps an error that occurred while creating a single clip, recording which
+/// marker and clip it belonged to.
+#[derive(Debug)]
+pub struct ClipError {
+    pub marker_id: String,
+    pub clip: String,
+    pub source: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl fmt::Display for ClipError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed to create clip {} for marker {}: {}",
+            self.clip, self.marker_id, self.source
+        )
+    }
+}
 
-    let mut clips: Vec<_> = clips.into_iter().map(Clip::from_path).collect();
-    clips.sort_by_key(|c| c.scene_id);
+impl std::error::Error for ClipError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
 
-    let iter = clips.into_iter().group_by(|c| c.scene_id);
-    let mut rng = create_seeded_rng();
-    let mut clips = vec![];
-    for (_, group) in &iter {
-        for (idx, clip) in group.enumerate() {
-            let rand = rng.next_u32();
-            clips.push((idx, rand, clip));
+/// The start of every clip in the joined video, followed by the end of the last one.
+/// Neighbouring clips overlap by `overlap` seconds.
+fn clip_boundaries(durations: &[f64], overlap: f64) -> Vec<f64> {
+    let mut boundaries = vec![0.0];
+    let mut position = 0.0;
+    for (index, duration) in durations.iter().enumerate() {
+        position += duration;
+        if index + 1 < durations.len() {
+            position -= overlap;
         }
+        boundaries.push(position);
     }
+    boundaries
+}
 
-    clips.sort_by_key(|(idx, rand, _)| Reverse((*idx, *rand)));
-    clips.into_iter().map(|(_, _, c)| c.path).collect()
+fn commandline_error<T>(output: Output) -> Result<T> {
+    Err(CommandError {
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+    }
+    .into())
 }
 
 impl Ffmpeg {
-    pub async fn new() -> Result<Self> {
+    pub async fn new(database: Arc<Database>) -> Result<Self> {
         let path = download_ffmpeg::download().await?;
+        let video_dir = Utf8PathBuf::from("./videos");
+        let cache = ClipCache::new(video_dir.join("clips"), database);
 
         Ok(Ffmpeg {
             path,
-            video_dir: Utf8PathBuf::from("./videos"),
+            video_dir,
+            cache,
+            encoders: Default::default(),
         })
     }
 
-    pub fn get_time_range(&self, marker: &Marker) -> (u32, Option<u32>) {
-        let start = marker.seconds;
+    /// The encoders supported by this ffmpeg build.
+    pub async fn encoders(&self) -> Result<&HashSet<String>> {
+        self.encoders
+            .get_or_try_init(|| async {
+                let output = Command::new(self.path.as_str())
+                    .args(["-hide_banner", "-encoders"])
+                    .output()
+                    .await?;
+                if !output.status.success() {
+                    return commandline_error(output);
+                }
+                Ok(encoding::parse_encoders(&String::from_utf8_lossy(
+                    &output.stdout,
+                )))
+            })
+            .await
+    }
+
+    /// Looks up the encoding profile with the given name (or the default one) and
+    /// makes sure ffmpeg supports it.
+    pub async fn encoding(&self, profile: Option<&str>) -> Result<ClipEncoding> {
+        let name = profile.unwrap_or(encoding::DEFAULT_PROFILE);
+        let profile = encoding::profiles()
+            .await
+            .into_iter()
+            .find(|p| p.name == name)
+            .ok_or_else(|| format!("no encoding profile named '{name}' found"))?;
+        profile.encoding.validate(self.encoders().await?)?;
+        Ok(profile.encoding)
+    }
+
+    /// The start and end of the marker. Uses the user's window if there is one, then the
+    /// marker's end time from Stash, and falls back to the start of the next marker.
+    pub fn get_time_range(
+        &self,
+        marker: &Marker,
+        window: Option<&MarkerWindow>,
+    ) -> (u32, Option<u32>) {
+        let start = window
+            .and_then(|w| w.start)
+            .unwrap_or(marker.seconds as u32);
+        if let Some(end) = window.and_then(|w| w.end) {
+            return (start, Some(end));
+        }
+        if let Some(end) = marker.end_seconds {
+            return (start, Some(end as u32));
+        }
+
         let next_marker = marker
             .scene
             .scene_markers
             .iter()
             .find(|m| m.seconds > marker.seconds);
-        if let Some(next) = next_marker {
-            (start as u32, Some(next.seconds as u32))
-        } else {
-            (start as u32, None)
+        (start, next_marker.map(|next| next.seconds as u32))
+    }
+
+    fn get_clip_offsets(
+        &self,
+        marker: &Marker,
+        window: Option<&MarkerWindow>,
+        options: &CreateVideoBody,
+    ) -> Vec<(u32, u32)> {
+        let (start, end) = self.get_time_range(marker, window);
+        options
+            .clip_strategy
+            .clip_offsets(start, end, options.clip_duration)
+    }
+
+    /// Runs ffmpeg to completion and returns its output, killing it if the job is
+    /// cancelled in the meantime.
+    async fn command_output(&self, mut command: Command, job: &JobHandle) -> Result<Output> {
+        let child = command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        tokio::select! {
+            output = child.wait_with_output() => Ok(output?),
+            _ = job.cancelled() => Err(format!("job {} was cancelled", job.id()).into()),
         }
     }
 
-    fn get_clip_offsets(&self, marker: &Marker, clip_duration_max: u32) -> Vec<(u32, u32)> {
-        let clip_lengths = [
-            (clip_duration_max / 2).max(2),
-            (clip_duration_max / 3).max(2),
-            (clip_duration_max / 4).max(2),
-        ];
+    /// Runs ffmpeg to completion, killing it if the job is cancelled in the meantime.
+    /// Partially written output is removed if the command does not succeed.
+    async fn run_command(
+        &self,
+        command: Command,
+        out_file: &Utf8Path,
+        job: &JobHandle,
+    ) -> Result<()> {
+        let result = match self.command_output(command, job).await {
+            Ok(output) if output.status.success() => Ok(()),
+            Ok(output) => commandline_error(output),
+            Err(e) => Err(e),
+        };
 
-        let mut rng = create_seeded_rng();
-        let (start, end) = self.get_time_range(marker);
-        let end = end.unwrap_or(start + clip_duration_max);
+        if result.is_err() && out_file.is_file() {
+            tracing::info!("removing partial output file {out_file}");
+            tokio::fs::remove_file(out_file).await?;
+        }
+        result
+    }
 
-        let mut offset = start;
-        let mut offsets = vec![];
-        while offset < end {
-            let duration = clip_lengths.choose(&mut rng).unwrap();
-            offsets.push((offset, *duration));
-            offset += duration;
+    /// First pass of the loudness normalization: measures the audio of the given input.
+    async fn measure_loudness(
+        &self,
+        input_args: &[&str],
+        target: &LoudnessTarget,
+        job: &JobHandle,
+    ) -> Result<LoudnessMeasurement> {
+        let filter = target.measure_filter();
+        let mut command = Command::new(self.path.as_str());
+        command
+            .args(["-hide_banner", "-nostats"])
+            .args(input_args)
+            .args(["-vn", "-af", &filter, "-f", "null", "-"]);
+        let output = self.command_output(command, job).await?;
+        if !output.status.success() {
+            return commandline_error(output);
         }
-        offsets
+        loudness::parse_measurement(&String::from_utf8_lossy(&output.stderr))
     }
 
     async fn create_clip(
         &self,
         url: &str,
-        start: u32,
-        duration: u32,
-        width: u32,
-        height: u32,
-        fps: f64,
+        parameters: &ClipParameters,
         out_file: &Utf8Path,
+        job: &JobHandle,
     ) -> Result<()> {
-        let clip_str = duration.to_string();
-        let seconds_str = start.to_string();
-        let filter = format!("scale={width}:{height}:force_original_aspect_ratio=decrease,pad={width}:{height}:-1:-1:color=black,fps={fps}",
-            fps=fps,
-        );
+        let clip_str = parameters.duration.to_string();
+        let seconds_str = parameters.start.to_string();
+        let ClipParameters {
+            width,
+            height,
+            fps,
+            encoding,
+            ..
+        } = parameters;
+        let mut filter = format!("scale={width}:{height}:force_original_aspect_ratio=decrease,pad={width}:{height}:-1:-1:color=black,fps={fps}");
+        if let Some(overlay) = &parameters.overlay {
+            filter.push(',');
+            filter.push_str(&overlay.filter());
+        }
+        let threads = FFMPEG_THREADS.to_string();
+        let encoding_args = encoding.args();
+        let audio_filter = match &parameters.loudness {
+            Some(target) => {
+                let input = ["-ss", &seconds_str, "-i", url, "-t", &clip_str];
+                let measured = self.measure_loudness(&input, target, job).await?;
+                target.normalize_filter(&measured)
+            }
+            None => None,
+        };
 
-        let args = vec![
+        let mut args = vec![
             "-hide_banner",
+            "-y",
             "-loglevel",
             "warning",
             "-ss",
@@ -227,33 +514,64 @@ impl Ffmpeg {
             url,
             "-t",
             clip_str.as_str(),
-            "-c:v",
-            "libx264",
-            "-preset",
-            "slow",
-            "-crf",
-            "22",
-            "-acodec",
-            "aac",
             "-vf",
             &filter,
-            "-ar",
-            "48000",
-            out_file.as_str(),
         ];
-        let output = Command::new(self.path.as_str()).args(args).output().await?;
-        if !output.status.success() {
-            commandline_error(output)
-        } else {
-            Ok(())
+        if let Some(audio_filter) = &audio_filter {
+            args.extend(["-af", audio_filter]);
+        }
+        // the clip is only moved into the cache once it is complete, so an encode that
+        // is killed midway doesn't leave a truncated clip under the cache's name.
+        let part_file = Utf8PathBuf::from(format!("{out_file}.part"));
+        args.extend(encoding_args.iter().map(String::as_str));
+        args.extend(["-threads", &threads, "-f", "mp4", part_file.as_str()]);
+        let mut command = Command::new(self.path.as_str());
+        command.args(args);
+        self.run_command(command, &part_file, job).await?;
+        tokio::fs::rename(&part_file, out_file).await?;
+        self.cache.insert(parameters).await
+    }
+
+    async fn encode_clip(
+        &self,
+        marker: &Marker,
+        url: &str,
+        parameters: &ClipParameters,
+        job: &JobHandle,
+    ) -> Result<()> {
+        let _lock = self.cache.lock(parameters).await;
+        // another job may have encoded the clip while this one was waiting
+        if self.cache.contains(parameters)? {
+            tracing::info!("clip {} was encoded by another job", parameters.file_name());
+            job.increase_progress().await;
+            return Ok(());
         }
+        let out_file = self.cache.path(parameters);
+        tracing::info!("creating clip {out_file}");
+        self.create_clip(url, parameters, &out_file, job)
+            .await
+            .map_err(|source| ClipError {
+                marker_id: marker.id.clone(),
+                clip: parameters.file_name(),
+                source,
+            })?;
+        job.increase_progress().await;
+        Ok(())
     }
 
     async fn write_markers_with_offsets(
         &self,
         id: &str,
+        clip_strategy: ClipStrategy,
         markers: &[(&Marker, Vec<(u32, u32)>)],
+        genders: &[Gender],
     ) -> Result<()> {
+        #[derive(Serialize)]
+        struct MarkersJson<'a> {
+            clip_strategy: ClipStrategy,
+            markers: Vec<MarkerJson<'a>>,
+        }
+
         #[derive(Serialize)]
         struct MarkerJson<'a> {
             scene: String,
@@ -266,111 +584,208 @@ impl Ffmpeg {
         let markers: Vec<_> = markers
             .iter()
             .map(|(marker, offsets)| MarkerJson {
-                scene: formatted_scene(marker),
+                scene: formatted_scene(marker, genders),
                 offsets,
                 scene_id: marker.scene.id.as_str(),
                 tag: &marker.primary_tag.name,
             })
             .collect();
-        let contents = serde_json::to_string_pretty(&markers)?;
+        let contents = serde_json::to_string_pretty(&MarkersJson {
+            clip_strategy,
+            markers,
+        })?;
         tokio::fs::write(path, contents).await?;
         Ok(())
     }
 
-    async fn initialize_progress(&self, total_items: usize) {
-        let mut progress = PROGRESS.lock().await;
-        progress.total = total_items;
-    }
-
-    async fn increase_progress(&self) {
-        let mut progress = PROGRESS.lock().await;
-        progress.finished += 1;
-    }
-
-    async fn reset_progress(&self) {
-        let mut progress = PROGRESS.lock().await;
-        *progress = Default::default();
-    }
-
-    pub async fn gather_clips(&self, output: &CreateVideoBody) -> Result<Vec<Utf8PathBuf>> {
-        tokio::fs::create_dir_all(&self.video_dir).await?;
+    pub async fn gather_clips<'a>(
+        &self,
+        output: &'a CreateVideoBody,
+        beat_grid: Option<&BeatGrid>,
+        job: &JobHandle,
+    ) -> Result<Vec<Clip<'a>>> {
+        tokio::fs::create_dir_all(self.cache.dir()).await?;
 
-        let markers: Vec<_> = output
+        let mut markers: Vec<_> = output
             .markers
             .iter()
-            .map(|m| (m, self.get_clip_offsets(m, output.clip_duration)))
+            .map(|m| {
+                let window = output.marker_windows.get(&m.id);
+                (m, self.get_clip_offsets(m, window, output))
+            })
             .collect();
-        self.write_markers_with_offsets(&output.id, markers.as_slice())
-            .await?;
+        match (beat_grid, &output.beat_sync) {
+            (Some(grid), Some(beat_sync)) => {
+                let windows: Vec<_> = markers
+                    .iter()
+                    .map(|(m, _)| {
+                        let window = output.marker_windows.get(&m.id);
+                        match self.get_time_range(m, window) {
+                            (start, Some(end)) => (start, end),
+                            (start, None) => (start, start + output.clip_duration),
+                        }
+                    })
+                    .collect();
+                beats::fit_clips_to_beat(
+                    grid,
+                    beat_sync,
+                    output.target_duration.as_ref(),
+                    &mut markers,
+                    &windows,
+                );
+                if markers.iter().all(|(_, offsets)| offsets.is_empty()) {
+                    return Err("none of the markers is long enough to cut on the beat".into());
+                }
+            }
+            _ => {
+                if let Some(target) = &output.target_duration {
+                    target.plan(&mut markers);
+                }
+            }
+        }
+        job.check_cancelled()?;
+        self.write_markers_with_offsets(
+            &output.id,
+            output.clip_strategy,
+            markers.as_slice(),
+            output.performer_genders(),
+        )
+        .await?;
 
         let total_items = markers
             .iter()
             .fold(0, |count, (_, offsets)| count + offsets.len());
-        self.initialize_progress(total_items).await;
+        job.set_total(total_items).await;
 
-        let mut paths = vec![];
+        let encoding = self.encoding(output.encoding_profile.as_deref()).await?;
+        let (width, height) = output.output_resolution.resolution();
+        let loudness = output
+            .loudness
+            .filter(|l| l.mode == LoudnessMode::PerClip)
+            .map(|l| l.target);
+        let mut clips = vec![];
         for (marker, offsets) in markers {
             let url = find_stream_url(marker);
-            let (width, height) = output.output_resolution.resolution();
+            let source_fingerprint = source_fingerprint(marker);
+            let overlay = output
+                .overlays
+                .as_ref()
+                .and_then(|o| o.lower_third(marker, height, output.performer_genders()));
             tracing::info!(
                 "computed {} offsets for marker {}",
                 offsets.len(),
                 marker.id
             );
             for (start, duration) in offsets {
-                let out_file = self.video_dir.join(format!(
-                    "{}_{}-{}.mp4",
-                    marker.scene.id,
+                let parameters = ClipParameters {
+                    scene_id: marker.scene.id.clone(),
+                    source_fingerprint: source_fingerprint.clone(),
                     start,
-                    start + duration
-                ));
-                if !out_file.is_file() {
-                    tracing::info!("creating clip {out_file}");
-                    self.create_clip(
-                        url,
-                        start,
-                        duration,
-                        width,
-                        height,
-                        output.output_fps as f64,
-                        &out_file,
-                    )
-                    .await?;
-                } else {
-                    tracing::info!("clip {out_file} already exists, skipping");
-                }
-                self.increase_progress().await;
-                paths.push(out_file);
+                    duration,
+                    width,
+                    height,
+                    fps: output.output_fps,
+                    encoding: encoding.clone(),
+                    loudness,
+                    overlay: overlay.clone(),
+                };
+                clips.push((marker, url, parameters));
             }
         }
-        self.reset_progress().await;
-        Ok(paths)
-    }
 
-    pub async fn compile_clips(
-        &self,
-        mut clips: Vec<Utf8PathBuf>,
-        options: &CreateVideoBody,
-    ) -> Result<Utf8PathBuf> {
-        tracing::info!("assembling {} clips into video", clips.len());
+        // register the clips before encoding, so they aren't evicted by other jobs
+        for (_, _, parameters) in &clips {
+            self.cache.use_clip(job.id(), parameters)?;
+        }
 
-        let clips = match options.clip_order {
-            ClipOrder::Random => {
-                let mut rng = rand::thread_rng();
-                clips.shuffle(&mut rng);
-                clips
+        // every clip is only encoded once, even if markers overlap.
+        let mut pending = HashSet::new();
+        let mut to_encode = vec![];
+        for (marker, url, parameters) in &clips {
+            if self.cache.contains(parameters)? {
+                tracing::info!("clip {} already exists, skipping", parameters.file_name());
+                job.increase_progress().await;
+            } else if pending.insert(parameters.file_name()) {
+                to_encode.push((*marker, *url, parameters));
+            } else {
+                job.increase_progress().await;
             }
-            ClipOrder::SceneOrder => intersperse_scene_clips(clips),
-        };
+        }
 
-        let lines: Vec<_> = clips
+        let workers = encoding_workers().await;
+        tracing::info!("encoding {} clips with {workers} workers", to_encode.len());
+        let encodes: Vec<_> = to_encode
             .into_iter()
-            .map(|file| format!("file '{}", file.file_name().unwrap()))
+            .map(|(marker, url, parameters)| self.encode_clip(marker, url, parameters, job))
+            .collect();
+        stream::iter(encodes)
+            .buffer_unordered(workers)
+            .try_collect::<()>()
+            .await?;
+        // all clips may have been cached, so no command noticed the cancellation
+        job.check_cancelled()?;
+
+        let mut result = vec![];
+        for (marker, _, parameters) in clips {
+            result.push(Clip {
+                path: self.cache.path(&parameters),
+                marker,
+                start: parameters.start,
+                duration: parameters.duration,
+            });
+        }
+        Ok(result)
+    }
+
+    /// Reads the duration of a video from ffmpeg's output.
+    async fn probe_duration(&self, path: &Utf8Path, job: &JobHandle) -> Result<f64> {
+        lazy_static! {
+            static ref DURATION_REGEX: Regex =
+                Regex::new(r#"Duration: (\d+):(\d+):(\d+(?:\.\d+)?)"#).unwrap();
+        }
+
+        let mut command = Command::new(self.path.as_str());
+        command.args(["-hide_banner", "-i", path.as_str()]);
+        let output = self.command_output(command, job).await?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let captures = DURATION_REGEX
+            .captures(&stderr)
+            .ok_or_else(|| format!("could not find the duration of {path}"))?;
+        let hours: f64 = captures[1].parse()?;
+        let minutes: f64 = captures[2].parse()?;
+        let seconds: f64 = captures[3].parse()?;
+        Ok(hours * 3600.0 + minutes * 60.0 + seconds)
+    }
+
+    /// Joins the clips with the concat demuxer, without re-encoding them. Returns the
+    /// boundaries between the clips in the output.
+    async fn concat_copy(
+        &self,
+        clips: &[Clip<'_>],
+        options: &CreateVideoBody,
+        destination: &Utf8Path,
+        job: &JobHandle,
+    ) -> Result<Vec<f64>> {
+        let mut durations = vec![];
+        for clip in clips {
+            durations.push(self.probe_duration(&clip.path, job).await?);
+        }
+        let lines: Vec<_> = clips
+            .iter()
+            .map(|clip| {
+                let relative = clip
+                    .path
+                    .strip_prefix(&self.video_dir)
+                    .unwrap_or(&clip.path);
+                format!("file '{relative}'")
+            })
             .collect();
         let file_content = lines.join("\n");
-        tokio::fs::write(self.video_dir.join("clips.txt"), file_content).await?;
-        let file_name = format!("{}.mp4", options.id);
-        let destination = self.video_dir.join(&file_name);
+        let clips_file = format!("clips-{}.txt", options.id);
+        tokio::fs::write(self.video_dir.join(&clips_file), file_content).await?;
+        let file_name = destination
+            .file_name()
+            .expect("destination must have a file name");
 
         let args = vec![
             "-hide_banner",
@@ -380,24 +795,583 @@ impl Ffmpeg {
             "-f",
             "concat",
             "-i",
-            "clips.txt",
+            &clips_file,
             "-c",
             "copy",
-            &file_name,
+            file_name,
         ];
 
-        let output = Command::new(self.path.as_str())
+        let mut command = Command::new(self.path.as_str());
+        command
             .args(args)
-            .current_dir(self.video_dir.canonicalize()?)
-            .output()
-            .await?;
+            .current_dir(self.video_dir.canonicalize()?);
+        let result = self.run_command(command, destination, job).await;
+        tokio::fs::remove_file(self.video_dir.join(&clips_file)).await?;
+        result?;
+        Ok(clip_boundaries(&durations, 0.0))
+    }
+
+    /// Joins the clips with `xfade` and `acrossfade`, re-encoding the whole video. Returns
+    /// the boundaries between the clips in the output, each at the start of a transition.
+    async fn concat_with_transitions(
+        &self,
+        clips: &[Clip<'_>],
+        transition: &Transition,
+        options: &CreateVideoBody,
+        destination: &Utf8Path,
+        job: &JobHandle,
+    ) -> Result<Vec<f64>> {
+        let mut durations = vec![];
+        for clip in clips {
+            durations.push(self.probe_duration(&clip.path, job).await?);
+        }
+        // a transition can't be longer than the clips it connects
+        let shortest = durations.iter().copied().fold(f64::INFINITY, f64::min);
+        let duration = transition.duration.min(shortest / 2.0);
+        if duration < transition.duration {
+            tracing::info!("shortened transitions to {duration:.2}s to fit the shortest clip");
+        }
+
+        let filter = transition_filter(&durations, transition.kind, duration);
+        let filter_file = self.video_dir.join(format!("filter-{}.txt", options.id));
+        tokio::fs::write(&filter_file, filter).await?;
+        let encoding = self.encoding(options.encoding_profile.as_deref()).await?;
+        let encoding_args = encoding.args();
+
+        let mut args = vec!["-hide_banner", "-y", "-loglevel", "warning"];
+        for clip in clips {
+            args.extend(["-i", clip.path.as_str()]);
+        }
+        args.extend([
+            "-filter_complex_script",
+            filter_file.as_str(),
+            "-map",
+            "[v]",
+            "-map",
+            "[a]",
+        ]);
+        args.extend(encoding_args.iter().map(String::as_str));
+        args.push(destination.as_str());
 
+        let mut command = Command::new(self.path.as_str());
+        command.args(args);
+        let result = self.run_command(command, destination, job).await;
+        tokio::fs::remove_file(&filter_file).await?;
+        result?;
+        Ok(clip_boundaries(&durations, duration))
+    }
+
+    /// Decodes the music and finds its beat.
+    async fn analyze_beats(&self, file: &Utf8Path, job: &JobHandle) -> Result<BeatGrid> {
+        let sample_rate = beats::ANALYSIS_SAMPLE_RATE.to_string();
+        let mut command = Command::new(self.path.as_str());
+        command
+            .args(["-hide_banner", "-loglevel", "error", "-i", file.as_str()])
+            .args(["-ac", "1", "-ar", &sample_rate, "-f", "f32le", "-"]);
+        let output = self.command_output(command, job).await?;
         if !output.status.success() {
             return commandline_error(output);
         }
 
-        tracing::info!("finished assembling video, result at {destination}");
+        let samples: Vec<f32> = output
+            .stdout
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        let grid = tokio::task::spawn_blocking(move || {
+            beats::detect_beats(&samples, beats::ANALYSIS_SAMPLE_RATE)
+        })
+        .await?
+        .ok_or_else(|| format!("could not find a beat in {file}"))?;
+        tracing::info!(
+            "detected {:.1} BPM in {file}, first beat at {:.2}s",
+            grid.bpm,
+            grid.first_beat
+        );
+        Ok(grid)
+    }
+
+    /// The beat of the first music file, if the video should be cut on the beat.
+    pub async fn beat_grid(
+        &self,
+        options: &CreateVideoBody,
+        job: &JobHandle,
+    ) -> Result<Option<BeatGrid>> {
+        match (&options.beat_sync, &options.music) {
+            (Some(_), Some(music)) => Ok(Some(self.analyze_beats(&music.files[0], job).await?)),
+            _ => Ok(None),
+        }
+    }
 
+    /// Joins the clips, cutting each of them to the length of its slot in the beat grid.
+    /// This re-encodes the video. Returns the boundaries between the clips in the output.
+    async fn concat_on_beat(
+        &self,
+        clips: &[Clip<'_>],
+        grid: &BeatGrid,
+        beat_sync: &BeatSync,
+        options: &CreateVideoBody,
+        destination: &Utf8Path,
+        job: &JobHandle,
+    ) -> Result<Vec<f64>> {
+        let fps = options.output_fps;
+        let frames = beats::clip_frames(grid, beat_sync.beats_per_clip, clips.len(), fps);
+        let mut filters = vec![];
+        let mut inputs = String::new();
+        for (i, frames) in frames.iter().enumerate() {
+            let duration = *frames as f64 / fps as f64;
+            filters.push(format!(
+                "[{i}:v]trim=end_frame={frames},setpts=PTS-STARTPTS[v{i}]"
+            ));
+            filters.push(format!(
+                "[{i}:a]atrim=duration={duration:.4},asetpts=PTS-STARTPTS[a{i}]"
+            ));
+            inputs.push_str(&format!("[v{i}][a{i}]"));
+        }
+        filters.push(format!("{inputs}concat=n={}:v=1:a=1[v][a]", clips.len()));
+
+        let filter_file = self.video_dir.join(format!("filter-{}.txt", options.id));
+        tokio::fs::write(&filter_file, filters.join(";\n")).await?;
+        let encoding = self.encoding(options.encoding_profile.as_deref()).await?;
+        let encoding_args = encoding.args();
+
+        let mut args = vec!["-hide_banner", "-y", "-loglevel", "warning"];
+        for clip in clips {
+            args.extend(["-i", clip.path.as_str()]);
+        }
+        args.extend([
+            "-filter_complex_script",
+            filter_file.as_str(),
+            "-map",
+            "[v]",
+            "-map",
+            "[a]",
+        ]);
+        args.extend(encoding_args.iter().map(String::as_str));
+        args.push(destination.as_str());
+
+        let mut command = Command::new(self.path.as_str());
+        command.args(args);
+        let result = self.run_command(command, destination, job).await;
+        tokio::fs::remove_file(&filter_file).await?;
+        result?;
+        let durations: Vec<_> = frames.iter().map(|f| *f as f64 / fps as f64).collect();
+        Ok(clip_boundaries(&durations, 0.0))
+    }
+
+    /// Puts background music under the finished video, replacing the file. The music
+    /// starts `music_start` seconds into the first file.
+    async fn add_music(
+        &self,
+        video: &Utf8Path,
+        music: &BackgroundMusic,
+        music_start: f64,
+        options: &CreateVideoBody,
+        job: &JobHandle,
+    ) -> Result<()> {
+        let video_duration = self.probe_duration(video, job).await?;
+        let mut playlist_duration = 0.0;
+        for file in &music.files {
+            playlist_duration += self.probe_duration(file, job).await?;
+        }
+        if playlist_duration <= 0.0 {
+            return Err("the background music is empty".into());
+        }
+        // repeat the playlist until it is at least as long as the video
+        let repeats = ((music_start + video_duration) / playlist_duration)
+            .ceil()
+            .max(1.0) as usize;
+        let inputs: Vec<_> = music
+            .files
+            .iter()
+            .cycle()
+            .take(music.files.len() * repeats)
+            .collect();
+        tracing::info!(
+            "adding {} music files to the video, repeated {repeats} times",
+            music.files.len()
+        );
+
+        let filter = audio::music_filter(music, inputs.len(), music_start, video_duration);
+        let filter_file = self.video_dir.join(format!("filter-{}.txt", options.id));
+        tokio::fs::write(&filter_file, filter).await?;
+        let encoding = self.encoding(options.encoding_profile.as_deref()).await?;
+        let audio_args = encoding.audio_args();
+        let out_file = self.video_dir.join(format!("{}.music.mp4", options.id));
+
+        let mut args = vec![
+            "-hide_banner",
+            "-y",
+            "-loglevel",
+            "warning",
+            "-i",
+            video.as_str(),
+        ];
+        for input in inputs {
+            args.extend(["-i", input.as_str()]);
+        }
+        args.extend([
+            "-filter_complex_script",
+            filter_file.as_str(),
+            "-map",
+            "0:v",
+            "-map",
+            "[a]",
+            "-c:v",
+            "copy",
+        ]);
+        args.extend(audio_args.iter().map(String::as_str));
+        args.push(out_file.as_str());
+
+        let mut command = Command::new(self.path.as_str());
+        command.args(args);
+        let result = self.run_command(command, &out_file, job).await;
+        tokio::fs::remove_file(&filter_file).await?;
+        result?;
+        tokio::fs::rename(&out_file, video).await?;
+        Ok(())
+    }
+
+    /// Normalizes the loudness of the finished video in two passes, replacing the file.
+    async fn normalize_loudness(
+        &self,
+        video: &Utf8Path,
+        target: &LoudnessTarget,
+        options: &CreateVideoBody,
+        job: &JobHandle,
+    ) -> Result<()> {
+        let measured = self
+            .measure_loudness(&["-i", video.as_str()], target, job)
+            .await?;
+        tracing::info!(
+            "measured {} LUFS and a true peak of {} dBTP in {video}",
+            measured.input_i,
+            measured.input_tp
+        );
+        let Some(filter) = target.normalize_filter(&measured) else {
+            tracing::info!("{video} is silent, not normalizing its loudness");
+            return Ok(());
+        };
+
+        let encoding = self.encoding(options.encoding_profile.as_deref()).await?;
+        let audio_args = encoding.audio_args();
+        let out_file = self.video_dir.join(format!("{}.loudnorm.mp4", options.id));
+        let mut args = vec![
+            "-hide_banner",
+            "-y",
+            "-loglevel",
+            "warning",
+            "-i",
+            video.as_str(),
+            "-map",
+            "0:v",
+            "-map",
+            "0:a",
+            "-c:v",
+            "copy",
+            "-af",
+            &filter,
+        ];
+        args.extend(audio_args.iter().map(String::as_str));
+        args.push(out_file.as_str());
+
+        let mut command = Command::new(self.path.as_str());
+        command.args(args);
+        self.run_command(command, &out_file, job).await?;
+        tokio::fs::rename(&out_file, video).await?;
+        Ok(())
+    }
+
+    /// Renders a title card: the text on a black background, with silent audio.
+    async fn create_card(
+        &self,
+        overlay: &TextOverlay,
+        duration: f64,
+        options: &CreateVideoBody,
+        out_file: &Utf8Path,
+        job: &JobHandle,
+    ) -> Result<()> {
+        let (width, height) = options.output_resolution.resolution();
+        let encoding = self.encoding(options.encoding_profile.as_deref()).await?;
+        let video_source = format!(
+            "color=c=black:s={width}x{height}:r={}:d={duration}",
+            options.output_fps
+        );
+        let audio_source = format!("anullsrc=r={}:cl=stereo", encoding.audio_sample_rate);
+        let duration = duration.to_string();
+        let filter = overlay.filter();
+        let encoding_args = encoding.args();
+
+        let mut args = vec![
+            "-hide_banner",
+            "-y",
+            "-loglevel",
+            "warning",
+            "-f",
+            "lavfi",
+            "-i",
+            &video_source,
+            "-f",
+            "lavfi",
+            "-i",
+            &audio_source,
+            "-t",
+            &duration,
+            "-vf",
+            &filter,
+        ];
+        args.extend(encoding_args.iter().map(String::as_str));
+        args.push(out_file.as_str());
+
+        let mut command = Command::new(self.path.as_str());
+        command.args(args);
+        self.run_command(command, out_file, job).await
+    }
+
+    /// Puts the intro and outro cards before and after the video, replacing the file.
+    async fn add_cards(
+        &self,
+        video: &Utf8Path,
+        overlays: &Overlays,
+        options: &CreateVideoBody,
+        job: &JobHandle,
+    ) -> Result<()> {
+        let (_, height) = options.output_resolution.resolution();
+        let id = &options.id;
+        let intro = self.video_dir.join(format!("{id}.intro.mp4"));
+        let outro = self.video_dir.join(format!("{id}.outro.mp4"));
+        let mut files = vec![];
+        if let Some(card) = &overlays.intro {
+            self.create_card(
+                &overlays.card(card, height),
+                card.duration,
+                options,
+                &intro,
+                job,
+            )
+            .await?;
+            files.push(intro.as_path());
+        }
+        files.push(video);
+        if let Some(card) = &overlays.outro {
+            self.create_card(
+                &overlays.card(card, height),
+                card.duration,
+                options,
+                &outro,
+                job,
+            )
+            .await?;
+            files.push(outro.as_path());
+        }
+        if files.len() == 1 {
+            return Ok(());
+        }
+
+        let lines: Vec<_> = files
+            .iter()
+            .map(|file| {
+                let name = file.file_name().expect("videos must have a file name");
+                format!("file '{name}'")
+            })
+            .collect();
+        let cards_file = format!("cards-{id}.txt");
+        tokio::fs::write(self.video_dir.join(&cards_file), lines.join("\n")).await?;
+        let out_file = self.video_dir.join(format!("{id}.cards.mp4"));
+        let out_name = out_file.file_name().expect("output must have a file name");
+
+        let mut command = Command::new(self.path.as_str());
+        command
+            .args([
+                "-hide_banner",
+                "-y",
+                "-loglevel",
+                "warning",
+                "-f",
+                "concat",
+                "-i",
+                &cards_file,
+                "-c",
+                "copy",
+                out_name,
+            ])
+            .current_dir(self.video_dir.canonicalize()?);
+        let result = self.run_command(command, &out_file, job).await;
+        tokio::fs::remove_file(self.video_dir.join(&cards_file)).await?;
+        for card in [&intro, &outro] {
+            if card.is_file() {
+                tokio::fs::remove_file(card).await?;
+            }
+        }
+        result?;
+        tokio::fs::rename(&out_file, video).await?;
+        Ok(())
+    }
+
+    /// Muxes the chapters into the video, replacing the file, and saves them next to it
+    /// for downloading.
+    async fn add_chapters(
+        &self,
+        video: &Utf8Path,
+        chapters: &[Chapter],
+        options: &CreateVideoBody,
+        job: &JobHandle,
+    ) -> Result<()> {
+        let id = &options.id;
+        let json = serde_json::to_string_pretty(chapters)?;
+        tokio::fs::write(self.chapters_path(id), json).await?;
+
+        let metadata_file = self.video_dir.join(format!("chapters-{id}.txt"));
+        tokio::fs::write(&metadata_file, chapters::ffmetadata(chapters)).await?;
+        let out_file = self.video_dir.join(format!("{id}.chapters.mp4"));
+        let mut command = Command::new(self.path.as_str());
+        command.args([
+            "-hide_banner",
+            "-y",
+            "-loglevel",
+            "warning",
+            "-i",
+            video.as_str(),
+            "-f",
+            "ffmetadata",
+            "-i",
+            metadata_file.as_str(),
+            "-map",
+            "0",
+            "-map_chapters",
+            "1",
+            "-c",
+            "copy",
+            out_file.as_str(),
+        ]);
+        let result = self.run_command(command, &out_file, job).await;
+        tokio::fs::remove_file(&metadata_file).await?;
+        result?;
+        tokio::fs::rename(&out_file, video).await?;
+        Ok(())
+    }
+
+    /// Where the chapters of a compilation are saved.
+    pub fn chapters_path(&self, id: &str) -> Utf8PathBuf {
+        self.video_dir.join(format!("chapters-{id}.json"))
+    }
+
+    /// Files that are only needed while a compilation is assembled.
+    fn intermediate_files(&self, id: &str) -> Vec<Utf8PathBuf> {
+        [
+            format!("{id}.partial.mp4"),
+            format!("{id}.intro.mp4"),
+            format!("{id}.outro.mp4"),
+            format!("{id}.cards.mp4"),
+            format!("{id}.music.mp4"),
+            format!("{id}.loudnorm.mp4"),
+            format!("{id}.chapters.mp4"),
+            format!("clips-{id}.txt"),
+            format!("filter-{id}.txt"),
+            format!("cards-{id}.txt"),
+            format!("chapters-{id}.txt"),
+        ]
+        .into_iter()
+        .map(|name| self.video_dir.join(name))
+        .collect()
+    }
+
+    /// Assembles the clips and applies all post-processing to a temporary file, which
+    /// only replaces `{id}.mp4` once everything succeeded. If anything fails, the
+    /// temporary files are removed.
+    pub async fn compile_clips(
+        &self,
+        clips: Vec<Clip<'_>>,
+        options: &CreateVideoBody,
+        beat_grid: Option<&BeatGrid>,
+        job: &JobHandle,
+    ) -> Result<Utf8PathBuf> {
+        let id = &options.id;
+        let work_file = self.video_dir.join(format!("{id}.partial.mp4"));
+        let result = self
+            .assemble_video(clips, options, beat_grid, &work_file, job)
+            .await;
+        if result.is_err() {
+            let mut files = self.intermediate_files(id);
+            files.push(self.chapters_path(id));
+            for file in files.iter().filter(|f| f.is_file()) {
+                // don't hide the error that stopped the job behind this one
+                if let Err(e) = tokio::fs::remove_file(file).await {
+                    tracing::warn!("could not remove {file}: {e}");
+                }
+            }
+        }
+        result?;
+
+        let destination = self.video_dir.join(format!("{id}.mp4"));
+        tokio::fs::rename(&work_file, &destination).await?;
+        tracing::info!("finished assembling video, result at {destination}");
         Ok(destination)
     }
+
+    async fn assemble_video(
+        &self,
+        clips: Vec<Clip<'_>>,
+        options: &CreateVideoBody,
+        beat_grid: Option<&BeatGrid>,
+        destination: &Utf8Path,
+        job: &JobHandle,
+    ) -> Result<()> {
+        tracing::info!("assembling {} clips into video", clips.len());
+
+        let order_options = OrderOptions {
+            seed: options.order_seed.unwrap_or(clips::DEFAULT_SEED),
+            tag_order: &options.tag_order,
+        };
+        let clips = clips::order_clips(clips, options.clip_order, &order_options);
+
+        job.check_cancelled()?;
+        let boundaries = match (&options.transition, beat_grid, &options.beat_sync) {
+            (_, Some(grid), Some(beat_sync)) => {
+                self.concat_on_beat(&clips, grid, beat_sync, options, destination, job)
+                    .await?
+            }
+            (Some(transition), _, _) if clips.len() > 1 => {
+                self.concat_with_transitions(&clips, transition, options, destination, job)
+                    .await?
+            }
+            _ => self.concat_copy(&clips, options, destination, job).await?,
+        };
+        let mut chapters = chapters::chapters(
+            &clips,
+            &boundaries,
+            options.chapters,
+            options.performer_genders(),
+        );
+        let mut intro_duration = 0.0;
+        if let Some(overlays) = &options.overlays {
+            self.add_cards(destination, overlays, options, job).await?;
+            intro_duration = overlays.intro_duration();
+            if !chapters.is_empty() {
+                add_card_chapters(&mut chapters, overlays);
+            }
+        }
+        if let Some(music) = &options.music {
+            // the first clip after the intro has to start on a beat
+            let music_start = beat_grid.map_or(0.0, |grid| {
+                (grid.first_beat - intro_duration).rem_euclid(grid.beat_length())
+            });
+            self.add_music(destination, music, music_start, options, job)
+                .await?;
+        }
+        if let Some(loudness) = &options.loudness {
+            if loudness.mode == LoudnessMode::Output {
+                self.normalize_loudness(destination, &loudness.target, options, job)
+                    .await?;
+            }
+        }
+        if !chapters.is_empty() {
+            self.add_chapters(destination, &chapters, options, job)
+                .await?;
+        } else if self.chapters_path(&options.id).is_file() {
+            // left over from an earlier run of the same compilation
+            tokio::fs::remove_file(self.chapters_path(&options.id)).await?;
+        }
+        job.check_cancelled()
+    }
 }
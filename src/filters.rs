@@ -0,0 +1,345 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    http::FilterMode,
+    stash_api::{
+        find_markers_query::{
+            CriterionModifier, DateCriterionInput,
+            FindMarkersQueryFindSceneMarkersSceneMarkers as Marker,
+            HierarchicalMultiCriterionInput, MultiCriterionInput, SceneMarkerFilterType,
+        },
+        find_scenes_query,
+    },
+    Result,
+};
+
+/// How the IDs of a criterion are matched.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Modifier {
+    /// At least one of them.
+    #[default]
+    Includes,
+    /// All of them.
+    IncludesAll,
+    /// None of them.
+    Excludes,
+}
+
+impl From<Modifier> for CriterionModifier {
+    fn from(modifier: Modifier) -> Self {
+        match modifier {
+            Modifier::Includes => CriterionModifier::INCLUDES,
+            Modifier::IncludesAll => CriterionModifier::INCLUDES_ALL,
+            Modifier::Excludes => CriterionModifier::EXCLUDES,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Criterion {
+    pub ids: Vec<String>,
+    #[serde(default)]
+    pub modifier: Modifier,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct TagCriterion {
+    pub ids: Vec<String>,
+    #[serde(default)]
+    pub modifier: Modifier,
+    /// How many levels of child tags match as well, -1 for all of them.
+    #[serde(default)]
+    pub depth: Option<i64>,
+}
+
+/// Criteria for the markers of a compilation. Markers have to match all of the
+/// criteria that are set.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct MarkerFilter {
+    #[serde(default)]
+    pub performers: Option<Criterion>,
+    /// Tags of the markers themselves.
+    #[serde(default)]
+    pub tags: Option<TagCriterion>,
+    /// Tags of the markers' scenes.
+    #[serde(default)]
+    pub scene_tags: Option<TagCriterion>,
+    /// Markers matching any of these are left out, whatever the other criteria are.
+    #[serde(default)]
+    pub exclusions: Exclusions,
+    /// ID of a saved marker filter in Stash. Its criteria are used instead of the ones
+    /// above, and are looked up every time the markers are fetched.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub saved_filter: Option<String>,
+}
+
+/// Tags and performers that keep markers out of the compilation.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Exclusions {
+    /// The marker's primary tag or one of its other tags.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub scene_tags: Vec<String>,
+    #[serde(default)]
+    pub performers: Vec<String>,
+}
+
+impl Exclusions {
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty() && self.scene_tags.is_empty() && self.performers.is_empty()
+    }
+
+    /// Whether the marker has any of the excluded tags or performers.
+    pub fn excludes(&self, marker: &Marker) -> bool {
+        let mut marker_tags =
+            std::iter::once(&marker.primary_tag.id).chain(marker.tags.iter().map(|t| &t.id));
+        marker_tags.any(|id| self.tags.contains(id))
+            || marker
+                .scene
+                .tags
+                .iter()
+                .any(|t| self.scene_tags.contains(&t.id))
+            || marker
+                .scene
+                .performers
+                .iter()
+                .any(|p| self.performers.contains(&p.id))
+    }
+}
+
+impl MarkerFilter {
+    /// The filter for the performers, tags or saved filter selected in the simple
+    /// filter modes.
+    pub fn from_selection(mode: FilterMode, ids: Vec<String>) -> Self {
+        match mode {
+            FilterMode::Performers => MarkerFilter {
+                performers: Some(Criterion {
+                    ids,
+                    modifier: Modifier::Includes,
+                }),
+                ..Default::default()
+            },
+            FilterMode::Tags => MarkerFilter {
+                tags: Some(TagCriterion {
+                    ids,
+                    modifier: Modifier::Includes,
+                    depth: None,
+                }),
+                ..Default::default()
+            },
+            FilterMode::SavedFilter => MarkerFilter {
+                saved_filter: ids.into_iter().next(),
+                ..Default::default()
+            },
+            FilterMode::Combined => Default::default(),
+        }
+    }
+
+    /// Whether the filter would match all markers.
+    pub fn is_empty(&self) -> bool {
+        let performers = self.performers.iter().map(|c| &c.ids);
+        let tags = self.tags.iter().chain(&self.scene_tags).map(|c| &c.ids);
+        performers.chain(tags).all(|ids| ids.is_empty())
+            && self.exclusions.is_empty()
+            && self.saved_filter.is_none()
+    }
+
+    /// The filter for Stash. A criterion can only be used once, so exclusions are only
+    /// sent for fields without another criterion and have to be applied to the results
+    /// with `Exclusions::excludes` as well.
+    pub fn scene_marker_filter(&self) -> SceneMarkerFilterType {
+        fn tag_input(
+            criterion: &Option<TagCriterion>,
+            excluded: &[String],
+        ) -> Option<HierarchicalMultiCriterionInput> {
+            let criterion = criterion.as_ref().filter(|c| !c.ids.is_empty());
+            match criterion {
+                Some(c) => Some(HierarchicalMultiCriterionInput {
+                    depth: c.depth,
+                    modifier: c.modifier.into(),
+                    value: Some(c.ids.clone()),
+                }),
+                None if !excluded.is_empty() => Some(HierarchicalMultiCriterionInput {
+                    depth: None,
+                    modifier: CriterionModifier::EXCLUDES,
+                    value: Some(excluded.to_vec()),
+                }),
+                None => None,
+            }
+        }
+
+        let performers = match self.performers.as_ref().filter(|c| !c.ids.is_empty()) {
+            Some(c) => Some(MultiCriterionInput {
+                modifier: c.modifier.into(),
+                value: Some(c.ids.clone()),
+            }),
+            None if !self.exclusions.performers.is_empty() => Some(MultiCriterionInput {
+                modifier: CriterionModifier::EXCLUDES,
+                value: Some(self.exclusions.performers.clone()),
+            }),
+            None => None,
+        };
+
+        SceneMarkerFilterType {
+            created_at: None,
+            scene_created_at: None,
+            scene_updated_at: None,
+            updated_at: None,
+            performers,
+            scene_date: None,
+            scene_tags: tag_input(&self.scene_tags, &self.exclusions.scene_tags),
+            tag_id: None,
+            tags: tag_input(&self.tags, &self.exclusions.tags),
+        }
+    }
+}
+
+/// Criteria for the scenes the markers are taken from. Scenes have to match all of
+/// the criteria that are set.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SceneFilter {
+    /// Studio IDs, sub-studios match as well.
+    #[serde(default)]
+    pub studios: Vec<String>,
+    /// Lowest rating, from 0 to 100.
+    #[serde(default)]
+    pub min_rating: Option<i64>,
+    /// Earliest scene date as `YYYY-MM-DD`, inclusive.
+    #[serde(default)]
+    pub date_from: Option<String>,
+    /// Latest scene date as `YYYY-MM-DD`, inclusive.
+    #[serde(default)]
+    pub date_to: Option<String>,
+    #[serde(default)]
+    pub organized: Option<bool>,
+    #[serde(default)]
+    pub min_o_counter: Option<i64>,
+    #[serde(default)]
+    pub min_play_count: Option<i64>,
+    #[serde(default)]
+    pub max_play_count: Option<i64>,
+}
+
+/// Value, second value and modifier of an integer criterion for an inclusive range.
+fn int_range(
+    min: Option<i64>,
+    max: Option<i64>,
+) -> Option<(i64, Option<i64>, find_scenes_query::CriterionModifier)> {
+    use find_scenes_query::CriterionModifier;
+
+    match (min, max) {
+        (Some(min), Some(max)) => Some((min, Some(max), CriterionModifier::BETWEEN)),
+        (Some(min), None) => Some((min - 1, None, CriterionModifier::GREATER_THAN)),
+        (None, Some(max)) => Some((max + 1, None, CriterionModifier::LESS_THAN)),
+        (None, None) => None,
+    }
+}
+
+impl SceneFilter {
+    pub fn validate(&self) -> Result<()> {
+        lazy_static! {
+            static ref DATE_REGEX: Regex = Regex::new(r"^\d{4}-\d{2}-\d{2}$").unwrap();
+        }
+
+        for date in self.date_from.iter().chain(&self.date_to) {
+            if !DATE_REGEX.is_match(date) {
+                return Err(format!("invalid date {date}, expected YYYY-MM-DD").into());
+            }
+        }
+        if let (Some(from), Some(to)) = (&self.date_from, &self.date_to) {
+            if from > to {
+                return Err("the scene date range ends before it starts".into());
+            }
+        }
+        if let Some(rating) = self.min_rating {
+            if !(0..=100).contains(&rating) {
+                return Err("the minimum rating must be between 0 and 100".into());
+            }
+        }
+        if let (Some(min), Some(max)) = (self.min_play_count, self.max_play_count) {
+            if min > max {
+                return Err("the minimum play count is higher than the maximum".into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the filter would match all scenes.
+    pub fn is_empty(&self) -> bool {
+        self.date_from.is_none() && self.date_to.is_none() && !self.needs_scene_query()
+    }
+
+    /// Whether the filter has criteria that markers can't be filtered by directly, so
+    /// the matching scenes have to be queried separately.
+    pub fn needs_scene_query(&self) -> bool {
+        !self.studios.is_empty()
+            || self.min_rating.is_some()
+            || self.organized.is_some()
+            || self.min_o_counter.is_some()
+            || self.min_play_count.is_some()
+            || self.max_play_count.is_some()
+    }
+
+    /// The date range, with open ends filled in.
+    fn date_range(&self) -> Option<(String, String)> {
+        if self.date_from.is_none() && self.date_to.is_none() {
+            return None;
+        }
+        let from = self.date_from.as_deref().unwrap_or("0001-01-01");
+        let to = self.date_to.as_deref().unwrap_or("9999-12-31");
+        Some((from.into(), to.into()))
+    }
+
+    /// The date criterion for the marker query.
+    pub fn scene_date(&self) -> Option<DateCriterionInput> {
+        self.date_range().map(|(from, to)| DateCriterionInput {
+            value: from,
+            value2: Some(to),
+            modifier: CriterionModifier::BETWEEN,
+        })
+    }
+
+    /// The variables for a query of all matching scenes.
+    pub fn scene_query(&self) -> find_scenes_query::Variables {
+        use find_scenes_query::{
+            CriterionModifier, DateCriterionInput, HierarchicalMultiCriterionInput,
+            IntCriterionInput,
+        };
+
+        let int_input = |min, max| {
+            int_range(min, max).map(|(value, value2, modifier)| IntCriterionInput {
+                value,
+                value2,
+                modifier,
+            })
+        };
+
+        find_scenes_query::Variables {
+            studios: Some(self.studios.clone())
+                .filter(|s| !s.is_empty())
+                .map(|studios| HierarchicalMultiCriterionInput {
+                    depth: Some(-1),
+                    modifier: CriterionModifier::INCLUDES,
+                    value: Some(studios),
+                }),
+            rating100: int_input(self.min_rating, None),
+            date: self.date_range().map(|(from, to)| DateCriterionInput {
+                value: from,
+                value2: Some(to),
+                modifier: CriterionModifier::BETWEEN,
+            }),
+            organized: self.organized,
+            o_counter: int_input(self.min_o_counter, None),
+            play_count: int_input(self.min_play_count, self.max_play_count),
+        }
+    }
+}
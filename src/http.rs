@@ -1,4 +1,9 @@
-use std::{cmp::Reverse, sync::Arc, time::Duration};
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
 use axum::{
     body::StreamBody,
@@ -9,26 +14,36 @@ use axum::{
     },
     Json,
 };
-use futures::{
-    stream::{self, Stream},
-    FutureExt,
-};
+use futures::stream::{self, Stream};
 use reqwest::{StatusCode, Url};
 use serde::{Deserialize, Serialize};
 use tokio_stream::StreamExt;
 use tokio_util::io::ReaderStream;
 
 use crate::{
-    config::{self, Config},
+    audio::BackgroundMusic,
+    beats::BeatSync,
+    chapters::{self, Chapter, ChapterFormat, ChapterMode},
+    clip_cache::{CacheUsage, PurgeResult},
+    clips::{ClipOrder, ClipStrategy, TargetDuration},
+    config::{self, Config, StashSettings},
+    database::VideoRecord,
+    encoding::{self, EncodingProfile},
     error::AppError,
-    ffmpeg::{self, ClipOrder},
+    ffmpeg::Transition,
+    filters::{Exclusions, MarkerFilter, SceneFilter},
+    jobs::{Job, JobHandle, JobStatus},
+    loudness::LoudnessNormalization,
+    overlays::Overlays,
+    performers::{self, Gender},
+    recipe::{self, Recipe, RecipeOverrides},
+    saved_filters,
     stash_api::{
         find_markers_query::{
-            self, CriterionModifier, FindFilterType,
-            FindMarkersQueryFindSceneMarkersSceneMarkers as GqlMarker,
-            HierarchicalMultiCriterionInput, MultiCriterionInput, SceneMarkerFilterType,
+            self, FindFilterType, FindMarkersQueryFindSceneMarkersSceneMarkers as GqlMarker,
         },
-        find_performers_query, find_tags_query, Api,
+        find_performers_query::{self, CriterionModifier, HierarchicalMultiCriterionInput},
+        find_tags_query, Api,
     },
     AppState,
 };
@@ -47,6 +62,20 @@ pub struct Performer {
     pub id: String,
     pub scene_count: i64,
     pub image_url: Option<String>,
+    pub gender: Option<Gender>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PerformerOptions {
+    /// Comma-separated genders to list, uses the configured ones if not set.
+    #[serde(default)]
+    pub genders: Option<String>,
+    #[serde(default)]
+    pub favorites_only: bool,
+    /// Comma-separated IDs of tags, performers need at least one of them.
+    #[serde(default)]
+    pub tags: Option<String>,
 }
 
 #[derive(Serialize, Debug)]
@@ -70,11 +99,55 @@ pub struct MarkerResult {
     pub gql: Vec<GqlMarker>,
 }
 
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Studio {
+    pub name: String,
+    pub id: String,
+    pub scene_count: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SavedFilter {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Scene {
+    pub id: String,
+    pub title: Option<String>,
+    pub file_name: Option<String>,
+    pub date: Option<String>,
+    pub studio: Option<String>,
+    pub rating: Option<i64>,
+    pub marker_count: usize,
+}
+
+/// A marker filter together with a filter for the markers' scenes.
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
+pub struct MarkerSearch {
+    #[serde(flatten)]
+    pub filter: MarkerFilter,
+    #[serde(default)]
+    pub scene_filter: Option<SceneFilter>,
+    /// Genders of the performers shown for the markers, uses the configured ones if not
+    /// set.
+    #[serde(default)]
+    pub genders: Option<Vec<Gender>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
 pub enum FilterMode {
     Performers,
     Tags,
+    /// Uses the `filter` of the options instead of the selected IDs.
+    Combined,
+    /// Uses the saved filter in Stash whose ID is selected.
+    SavedFilter,
 }
 
 #[derive(Deserialize, Debug)]
@@ -82,15 +155,48 @@ pub enum FilterMode {
 pub struct MarkerOptions {
     pub selected_ids: String,
     pub mode: FilterMode,
+    /// Comma-separated IDs of tags whose markers are left out.
+    #[serde(default)]
+    pub excluded_tags: Option<String>,
+    /// Comma-separated IDs of tags whose scenes' markers are left out.
+    #[serde(default)]
+    pub excluded_scene_tags: Option<String>,
+    /// Comma-separated IDs of performers whose scenes' markers are left out.
+    #[serde(default)]
+    pub excluded_performers: Option<String>,
+    /// Comma-separated genders of the performers shown for the markers, uses the
+    /// configured ones if not set.
+    #[serde(default)]
+    pub genders: Option<String>,
+}
+
+impl MarkerOptions {
+    pub fn exclusions(&self) -> Exclusions {
+        let ids = |list: &Option<String>| -> Vec<String> {
+            list.iter()
+                .flat_map(|l| l.split(','))
+                .filter(|id| !id.is_empty())
+                .map(From::from)
+                .collect()
+        };
+        Exclusions {
+            tags: ids(&self.excluded_tags),
+            scene_tags: ids(&self.excluded_scene_tags),
+            performers: ids(&self.excluded_performers),
+        }
+    }
 }
 
-#[derive(Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, clap::ValueEnum)]
 pub enum Resolution {
     #[serde(rename = "720")]
+    #[value(name = "720")]
     SevenTwenty,
     #[serde(rename = "1080")]
+    #[value(name = "1080")]
     TenEighty,
     #[serde(rename = "4K")]
+    #[value(name = "4K")]
     FourK,
 }
 
@@ -104,18 +210,126 @@ impl Resolution {
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct CreateVideoBody {
     pub select_mode: FilterMode,
     pub selected_ids: Vec<String>,
+    /// Performers, tags and scene tags to filter by, for the `combined` mode. In the
+    /// other modes only its exclusions are used.
+    #[serde(default)]
+    pub filter: Option<MarkerFilter>,
+    /// Studio, rating, date and play criteria for the markers' scenes.
+    #[serde(default)]
+    pub scene_filter: Option<SceneFilter>,
     pub clip_order: ClipOrder,
+    /// Seed for random clip orders. A random one is chosen and saved in the recipe if
+    /// not set.
+    #[serde(default)]
+    pub order_seed: Option<u64>,
+    /// Tag IDs in the order they should appear in, for the `tag` order.
+    #[serde(default)]
+    pub tag_order: Vec<String>,
     pub clip_duration: u32,
+    #[serde(default)]
+    pub clip_strategy: ClipStrategy,
+    /// Shortens the compilation to a total length, uses all clips if not set.
+    #[serde(default)]
+    pub target_duration: Option<TargetDuration>,
     pub output_resolution: Resolution,
     pub output_fps: u32,
     pub selected_markers: Vec<String>,
     pub markers: Vec<GqlMarker>,
     pub id: String,
+    /// Re-encodes the video with transitions between clips, instead of just joining them.
+    #[serde(default)]
+    pub transition: Option<Transition>,
+    /// Music to put under the compilation.
+    #[serde(default)]
+    pub music: Option<BackgroundMusic>,
+    /// Cuts the clips on the beat of the first music file. Requires `music`.
+    #[serde(default)]
+    pub beat_sync: Option<BeatSync>,
+    /// Normalizes the loudness of the clips or of the whole compilation.
+    #[serde(default)]
+    pub loudness: Option<LoudnessNormalization>,
+    /// Title cards and text drawn onto the clips.
+    #[serde(default)]
+    pub overlays: Option<Overlays>,
+    /// What the chapters of the video cover.
+    #[serde(default)]
+    pub chapters: ChapterMode,
+    /// Genders of the performers named in overlays, chapters and the markers file.
+    /// Set to the configured ones when the video is submitted.
+    #[serde(default)]
+    pub performer_genders: Option<Vec<Gender>>,
+    /// Name of the encoding profile, uses the default profile if not set.
+    #[serde(default)]
+    pub encoding_profile: Option<String>,
+    /// Start and end times set by the user, by marker ID.
+    #[serde(default)]
+    pub marker_windows: HashMap<String, MarkerWindow>,
+}
+
+impl CreateVideoBody {
+    pub fn performer_genders(&self) -> &[Gender] {
+        self.performer_genders.as_deref().unwrap_or_default()
+    }
+
+    /// The filter the markers were selected with.
+    pub fn marker_filter(&self) -> MarkerFilter {
+        match self.select_mode {
+            FilterMode::Combined => self.filter.clone().unwrap_or_default(),
+            mode => MarkerFilter {
+                exclusions: self
+                    .filter
+                    .as_ref()
+                    .map(|f| f.exclusions.clone())
+                    .unwrap_or_default(),
+                ..MarkerFilter::from_selection(mode, self.selected_ids.clone())
+            },
+        }
+    }
+}
+
+/// Stores the options without their markers, which are kept by ID in
+/// `selected_markers`. Marker data from Stash gains fields over time, so a stored copy
+/// of it would stop being readable. The markers are fetched again when needed.
+pub mod stored_options {
+    use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+    use serde_json::Value;
+
+    use super::CreateVideoBody;
+
+    pub fn serialize<S: Serializer>(
+        options: &CreateVideoBody,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut value = serde_json::to_value(options).map_err(ser::Error::custom)?;
+        if let Some(object) = value.as_object_mut() {
+            object.remove("markers");
+        }
+        value.serialize(serializer)
+    }
+
+    /// Also reads options that were stored together with their markers.
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<CreateVideoBody, D::Error> {
+        let mut value = Value::deserialize(deserializer)?;
+        if let Some(object) = value.as_object_mut() {
+            object.insert("markers".into(), Value::Array(vec![]));
+        }
+        serde_json::from_value(value).map_err(de::Error::custom)
+    }
+}
+
+/// Overrides the part of the scene a marker's clips are taken from, in seconds.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct MarkerWindow {
+    pub start: Option<u32>,
+    pub end: Option<u32>,
 }
 
 fn add_api_key(url: &str, api_key: &str) -> String {
@@ -124,8 +338,16 @@ fn add_api_key(url: &str, api_key: &str) -> String {
     url.to_string()
 }
 
+#[derive(Deserialize, Debug)]
+pub struct TagOptions {
+    /// Lists the tags of performers instead of the tags of markers, with the number of
+    /// performers as the count.
+    #[serde(default)]
+    pub performers: bool,
+}
+
 #[axum::debug_handler]
-pub async fn fetch_tags() -> Result<Json<Vec<Tag>>, AppError> {
+pub async fn fetch_tags(Query(query): Query<TagOptions>) -> Result<Json<Vec<Tag>>, AppError> {
     let api = Api::load_config().await?;
     let tags = api.find_tags(find_tags_query::Variables {}).await?;
     let mut tags: Vec<_> = tags
@@ -133,7 +355,12 @@ pub async fn fetch_tags() -> Result<Json<Vec<Tag>>, AppError> {
         .map(|t| Tag {
             name: t.name,
             id: t.id,
-            count: t.scene_marker_count.unwrap_or_default(),
+            count: if query.performers {
+                t.performer_count
+            } else {
+                t.scene_marker_count
+            }
+            .unwrap_or_default(),
         })
         .filter(|t| t.count > 0)
         .collect();
@@ -145,11 +372,32 @@ pub async fn fetch_tags() -> Result<Json<Vec<Tag>>, AppError> {
 }
 
 #[axum::debug_handler]
-pub async fn fetch_performers() -> Result<Json<Vec<Performer>>, AppError> {
+pub async fn fetch_performers(
+    Query(query): Query<PerformerOptions>,
+) -> Result<Json<Vec<Performer>>, AppError> {
     let config = Config::get().await?;
     let api = Api::from_config(&config);
+    let genders = match &query.genders {
+        Some(list) => performers::parse_genders(list)?,
+        None => config.performer_genders.clone(),
+    };
+    let tag_ids: Vec<String> = query
+        .tags
+        .iter()
+        .flat_map(|t| t.split(','))
+        .filter(|id| !id.is_empty())
+        .map(From::from)
+        .collect();
+    let tags = (!tag_ids.is_empty()).then_some(HierarchicalMultiCriterionInput {
+        depth: None,
+        modifier: CriterionModifier::INCLUDES,
+        value: Some(tag_ids),
+    });
     let performers = api
-        .find_performers(find_performers_query::Variables {})
+        .find_performers(find_performers_query::Variables {
+            favorites: query.favorites_only.then_some(true),
+            tags,
+        })
         .await?;
     let mut performers: Vec<_> = performers
         .into_iter()
@@ -158,8 +406,9 @@ pub async fn fetch_performers() -> Result<Json<Vec<Performer>>, AppError> {
             scene_count: p.scene_count.unwrap_or_default(),
             name: p.name,
             image_url: p.image_path.map(|url| add_api_key(&url, &config.api_key)),
+            gender: p.gender.as_ref().and_then(Into::into),
         })
-        .filter(|p| p.scene_count > 0)
+        .filter(|p| p.scene_count > 0 && performers::shows_gender(&genders, p.gender))
         .collect();
     performers.sort_by_key(|t| Reverse(t.scene_count));
 
@@ -169,63 +418,174 @@ pub async fn fetch_performers() -> Result<Json<Vec<Performer>>, AppError> {
 }
 
 #[axum::debug_handler]
-pub async fn fetch_markers(
-    state: State<Arc<AppState>>,
-    Query(query): Query<MarkerOptions>,
-) -> Result<Json<MarkerResult>, AppError> {
-    let config = Config::get().await?;
-    let api = Api::from_config(&config);
-    tracing::info!("fetching markers for query {query:?}");
+pub async fn fetch_studios() -> Result<Json<Vec<Studio>>, AppError> {
+    let api = Api::load_config().await?;
+    let mut studios: Vec<_> = api
+        .find_studios()
+        .await?
+        .into_iter()
+        .map(|s| Studio {
+            name: s.name,
+            id: s.id,
+            scene_count: s.scene_count.unwrap_or_default(),
+        })
+        .filter(|s| s.scene_count > 0)
+        .collect();
+    studios.sort_by_key(|s| Reverse(s.scene_count));
 
-    let mut scene_filter = SceneMarkerFilterType {
-        created_at: None,
-        scene_created_at: None,
-        scene_updated_at: None,
-        updated_at: None,
-        performers: None,
-        scene_date: None,
-        scene_tags: None,
-        tag_id: None,
-        tags: None,
-    };
+    Ok(Json(studios))
+}
 
-    let ids: Vec<_> = query.selected_ids.split(',').map(From::from).collect();
+/// Lists the saved scene marker filters in Stash.
+#[axum::debug_handler]
+pub async fn fetch_saved_filters() -> Result<Json<Vec<SavedFilter>>, AppError> {
+    let api = Api::load_config().await?;
+    let mut filters: Vec<_> = api
+        .find_saved_filters()
+        .await?
+        .into_iter()
+        .map(|f| SavedFilter {
+            id: f.id,
+            name: f.name,
+        })
+        .collect();
+    filters.sort_by_key(|f| f.name.to_lowercase());
 
-    match query.mode {
-        FilterMode::Performers => {
-            scene_filter.performers = Some(MultiCriterionInput {
-                modifier: CriterionModifier::INCLUDES,
-                value: Some(ids),
-            });
-        }
-        FilterMode::Tags => {
-            scene_filter.tags = Some(HierarchicalMultiCriterionInput {
-                depth: None,
-                modifier: CriterionModifier::INCLUDES,
-                value: Some(ids),
-            });
-        }
-    }
+    Ok(Json(filters))
+}
 
-    let gql_markers = api
-        .find_markers(find_markers_query::Variables {
-            filter: Some(FindFilterType {
+/// Lists the scenes matching a scene filter, to preview what it selects.
+#[axum::debug_handler]
+pub async fn search_scenes(Json(filter): Json<SceneFilter>) -> Result<Json<Vec<Scene>>, AppError> {
+    tracing::info!("fetching scenes for filter {filter:?}");
+    filter
+        .validate()
+        .map_err(|e| AppError::BadRequest(e.to_string()))?;
+    let api = Api::load_config().await?;
+    let scenes = api
+        .find_scenes(filter.scene_query())
+        .await?
+        .into_iter()
+        .map(|s| Scene {
+            id: s.id,
+            title: s.title,
+            file_name: s.files.into_iter().next().map(|f| f.basename),
+            date: s.date,
+            studio: s.studio.map(|s| s.name),
+            rating: s.rating100,
+            marker_count: s.scene_markers.len(),
+        })
+        .collect();
+
+    Ok(Json(scenes))
+}
+
+/// Fetches all markers matching the filter, from scenes matching the scene filter.
+pub async fn query_markers(
+    api: &Api,
+    filter: &MarkerFilter,
+    scene_filter: Option<&SceneFilter>,
+) -> crate::Result<Vec<GqlMarker>> {
+    let (find_filter, mut scene_marker_filter, saved_exclusions) = match &filter.saved_filter {
+        Some(id) => {
+            let saved = api
+                .find_saved_filters()
+                .await?
+                .into_iter()
+                .find(|f| &f.id == id)
+                .ok_or_else(|| format!("no saved marker filter with ID {id} found"))?;
+            let query = saved_filters::translate(&saved.filter)
+                .map_err(|e| format!("could not use saved filter '{}': {e}", saved.name))?;
+            (query.filter, query.scene_marker_filter, query.exclusions)
+        }
+        None => {
+            let find_filter = FindFilterType {
                 per_page: Some(-1),
                 page: None,
                 q: None,
                 sort: None,
                 direction: None,
-            }),
-            scene_marker_filter: Some(scene_filter),
+            };
+            (
+                find_filter,
+                filter.scene_marker_filter(),
+                Exclusions::default(),
+            )
+        }
+    };
+    if let Some(scene_date) = scene_filter.and_then(|f| f.scene_date()) {
+        scene_marker_filter.scene_date = Some(scene_date);
+    }
+    let mut markers = api
+        .find_markers(find_markers_query::Variables {
+            filter: Some(find_filter),
+            scene_marker_filter: Some(scene_marker_filter),
         })
         .await?;
+    markers.retain(|m| !filter.exclusions.excludes(m) && !saved_exclusions.excludes(m));
+
+    if let Some(scene_filter) = scene_filter.filter(|f| f.needs_scene_query()) {
+        let scenes = api.find_scenes(scene_filter.scene_query()).await?;
+        let scene_ids: HashSet<_> = scenes.into_iter().map(|s| s.id).collect();
+        markers.retain(|m| scene_ids.contains(&m.scene.id));
+    }
+    Ok(markers)
+}
+
+#[axum::debug_handler]
+pub async fn fetch_markers(
+    state: State<Arc<AppState>>,
+    Query(query): Query<MarkerOptions>,
+) -> Result<Json<MarkerResult>, AppError> {
+    tracing::info!("fetching markers for query {query:?}");
+    let ids: Vec<_> = query.selected_ids.split(',').map(From::from).collect();
+    let filter = MarkerFilter {
+        exclusions: query.exclusions(),
+        ..MarkerFilter::from_selection(query.mode, ids)
+    };
+    let genders = match &query.genders {
+        Some(list) => Some(performers::parse_genders(list)?),
+        None => None,
+    };
+    marker_result(&state, &filter, None, genders).await
+}
+
+/// Like `fetch_markers`, but for a combined filter and an optional scene filter.
+#[axum::debug_handler]
+pub async fn search_markers(
+    state: State<Arc<AppState>>,
+    Json(search): Json<MarkerSearch>,
+) -> Result<Json<MarkerResult>, AppError> {
+    tracing::info!("fetching markers for search {search:?}");
+    let scene_filter = search.scene_filter.as_ref().filter(|f| !f.is_empty());
+    if search.filter.is_empty() && scene_filter.is_none() {
+        return Err(AppError::BadRequest("the filter is empty".into()));
+    }
+    if let Some(scene_filter) = scene_filter {
+        scene_filter
+            .validate()
+            .map_err(|e| AppError::BadRequest(e.to_string()))?;
+    }
+    marker_result(&state, &search.filter, scene_filter, search.genders.clone()).await
+}
+
+async fn marker_result(
+    state: &AppState,
+    filter: &MarkerFilter,
+    scene_filter: Option<&SceneFilter>,
+    genders: Option<Vec<Gender>>,
+) -> Result<Json<MarkerResult>, AppError> {
+    let config = Config::get().await?;
+    let api = Api::from_config(&config);
+    let genders = genders.unwrap_or_else(|| config.performer_genders.clone());
+    let gql_markers = query_markers(&api, filter, scene_filter).await?;
 
     let api_key = &config.api_key;
     let dtos = gql_markers
         .clone()
         .into_iter()
         .map(|m| {
-            let (_, end) = state.ffmpeg.get_time_range(&m);
+            let (_, end) = state.ffmpeg.get_time_range(&m, None);
             Marker {
                 id: m.id,
                 primary_tag: m.primary_tag.name,
@@ -234,7 +594,15 @@ pub async fn fetch_markers(
                 start: m.seconds as u32,
                 end,
                 file_name: m.scene.files[0].basename.clone(),
-                performers: m.scene.performers.into_iter().map(|p| p.name).collect(),
+                performers: m
+                    .scene
+                    .performers
+                    .into_iter()
+                    .filter(|p| {
+                        performers::shows_gender(&genders, p.gender.as_ref().and_then(Into::into))
+                    })
+                    .map(|p| p.name)
+                    .collect(),
                 scene_title: m.scene.title,
             }
         })
@@ -247,42 +615,276 @@ pub async fn fetch_markers(
 }
 
 async fn create_video_inner(
-    state: State<Arc<AppState>>,
-    mut body: CreateVideoBody,
+    state: Arc<AppState>,
+    body: CreateVideoBody,
+    job: JobHandle,
 ) -> Result<(), AppError> {
+    let beat_grid = state.ffmpeg.beat_grid(&body, &job).await?;
+    job.check_cancelled()?;
+    let clips = state
+        .ffmpeg
+        .gather_clips(&body, beat_grid.as_ref(), &job)
+        .await?;
+    let video = state
+        .ffmpeg
+        .compile_clips(clips, &body, beat_grid.as_ref(), &job)
+        .await?;
+    job.set_output(&video).await?;
+
+    if let Some(max_size) = Config::get().await?.max_clip_cache_size_mb {
+        state.ffmpeg.cache.evict(max_size).await?;
+    }
+
+    Ok(())
+}
+
+/// Queues a job to create the video and saves its recipe next to the output.
+pub async fn submit_video(state: Arc<AppState>, mut body: CreateVideoBody) -> Result<(), AppError> {
+    // fail early instead of queueing a job that can't succeed
+    state
+        .ffmpeg
+        .encoding(body.encoding_profile.as_deref())
+        .await?;
+    if let Some(music) = &body.music {
+        if music.files.is_empty() {
+            return Err(AppError::BadRequest("no music files given".into()));
+        }
+        if let Some(missing) = music.files.iter().find(|f| !f.is_file()) {
+            return Err(AppError::NotFound(format!(
+                "music file {missing} does not exist"
+            )));
+        }
+    }
+    if let Some(overlays) = &body.overlays {
+        overlays
+            .validate()
+            .map_err(|e| AppError::BadRequest(e.to_string()))?;
+    }
+    if let Some(scene_filter) = &body.scene_filter {
+        scene_filter
+            .validate()
+            .map_err(|e| AppError::BadRequest(e.to_string()))?;
+    }
+    if let Some(loudness) = &body.loudness {
+        loudness
+            .target
+            .validate()
+            .map_err(|e| AppError::BadRequest(e.to_string()))?;
+    }
+    if body.beat_sync.is_some() && body.music.is_none() {
+        return Err(AppError::BadRequest(
+            "cutting on the beat requires background music".into(),
+        ));
+    }
+    if body.beat_sync.is_some() && body.transition.is_some() {
+        return Err(AppError::BadRequest(
+            "cutting on the beat can't be combined with transitions".into(),
+        ));
+    }
+    let mut shortest_clip = body.clip_duration;
+    for marker in body
+        .markers
+        .iter()
+        .filter(|m| body.selected_markers.contains(&m.id))
+    {
+        let window = body.marker_windows.get(&marker.id);
+        let (start, end) = state.ffmpeg.get_time_range(marker, window);
+        if let Some(end) = end {
+            if end <= start {
+                return Err(AppError::BadRequest(format!(
+                    "marker {} ends before it starts",
+                    marker.id
+                )));
+            }
+        }
+        let offsets = body
+            .clip_strategy
+            .clip_offsets(start, end, body.clip_duration);
+        if let Some(shortest) = offsets.iter().map(|(_, duration)| *duration).min() {
+            shortest_clip = shortest_clip.min(shortest);
+        }
+    }
+    if let Some(transition) = &body.transition {
+        transition
+            .validate(shortest_clip as f64)
+            .map_err(|e| AppError::BadRequest(e.to_string()))?;
+    }
+
+    body.order_seed.get_or_insert_with(rand::random);
+    if body.performer_genders.is_none() {
+        body.performer_genders = Some(Config::get().await?.performer_genders);
+    }
+    let recipe = Recipe::new(&body);
+    let video_dir = state.ffmpeg.video_dir.clone();
     body.markers
         .retain(|e| body.selected_markers.contains(&e.id));
-    let clips = state.ffmpeg.gather_clips(&body).await?;
-    state.ffmpeg.compile_clips(clips, &body).await?;
+    let jobs = state.jobs.clone();
+    let options = body.clone();
+    jobs.submit(&options, move |job| create_video_inner(state, body, job))
+        .await?;
+    recipe.save(&video_dir).await?;
 
     Ok(())
 }
 
 #[axum::debug_handler]
 pub async fn create_video(
-    state: State<Arc<AppState>>,
+    State(state): State<Arc<AppState>>,
     Json(body): Json<CreateVideoBody>,
-) -> StatusCode {
+) -> Result<StatusCode, AppError> {
     tracing::debug!("received json body: {:?}", body);
-    tokio::spawn(async move {
-        if let Err(e) = create_video_inner(state, body).await {
-            tracing::error!("error: {e:?}");
+    submit_video(state, body).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize, Debug)]
+pub struct RerunResponse {
+    pub id: String,
+}
+
+#[axum::debug_handler]
+pub async fn rerun_video(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    overrides: Option<Json<RecipeOverrides>>,
+) -> Result<Json<RerunResponse>, AppError> {
+    let path = recipe::recipe_path(&state.ffmpeg.video_dir, &id);
+    if !path.is_file() {
+        return Err(AppError::NotFound(format!(
+            "no recipe found for video {id}"
+        )));
+    }
+    let recipe = Recipe::load(&path).await?;
+    let overrides = overrides.map(|Json(o)| o).unwrap_or_default();
+    let api = Api::load_config().await?;
+    let options = recipe.to_options(&api, &overrides).await?;
+    let id = options.id.clone();
+    tracing::info!("re-rendering video {} as {id}", recipe.options.id);
+    submit_video(state, options).await?;
+
+    Ok(Json(RerunResponse { id }))
+}
+
+/// Streams the state of a job once per second. The stream ends with a `done`, `failed`
+/// or `cancelled` event once the job is over.
+#[axum::debug_handler]
+pub async fn get_progress(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Sse<impl Stream<Item = Result<Event, serde_json::Error>>>, AppError> {
+    if state.jobs.get(&id).await.is_none() {
+        return Err(AppError::NotFound(format!("no job with id {id}")));
+    }
+
+    let stream = stream::unfold(Some(state), move |state| {
+        let id = id.clone();
+        async move {
+            let state = state?;
+            let job = state.jobs.get(&id).await?;
+            let event = match job.status {
+                JobStatus::Finished => Event::default().event("done"),
+                JobStatus::Failed => Event::default().event("failed"),
+                JobStatus::Cancelled => Event::default().event("cancelled"),
+                JobStatus::Queued | JobStatus::Running => Event::default(),
+            };
+            let next = if job.status.is_done() {
+                None
+            } else {
+                Some(state)
+            };
+            Some((event.json_data(job), next))
         }
-    });
+    })
+    .throttle(Duration::from_secs(1));
 
-    StatusCode::NO_CONTENT
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
 }
 
 #[axum::debug_handler]
-pub async fn get_progress() -> Sse<impl Stream<Item = Result<Event, serde_json::Error>>> {
-    let stream = futures::StreamExt::flat_map(stream::repeat_with(ffmpeg::get_progress), |f| {
-        f.into_stream()
-    });
-    let stream = stream
-        .map(|p| Event::default().json_data(p))
-        .throttle(Duration::from_secs(1));
+pub async fn list_jobs(State(state): State<Arc<AppState>>) -> Json<Vec<Job>> {
+    Json(state.jobs.list().await)
+}
 
-    Sse::new(stream).keep_alive(KeepAlive::default())
+#[axum::debug_handler]
+pub async fn get_job(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<Job>, AppError> {
+    match state.jobs.get(&id).await {
+        Some(job) => Ok(Json(job)),
+        None => Err(AppError::NotFound(format!("no job with id {id}"))),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn list_videos(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<VideoRecord>>, AppError> {
+    let videos = state.database.list_videos()?;
+    Ok(Json(videos))
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EncodingProfileInfo {
+    #[serde(flatten)]
+    pub profile: EncodingProfile,
+    /// Whether the ffmpeg build supports the profile's encoders.
+    pub available: bool,
+}
+
+#[axum::debug_handler]
+pub async fn list_encoding_profiles(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<EncodingProfileInfo>>, AppError> {
+    let encoders = state.ffmpeg.encoders().await?;
+    let profiles = encoding::profiles()
+        .await
+        .into_iter()
+        .map(|profile| EncodingProfileInfo {
+            available: profile.encoding.validate(encoders).is_ok(),
+            profile,
+        })
+        .collect();
+    Ok(Json(profiles))
+}
+
+#[axum::debug_handler]
+pub async fn get_cache_usage(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<CacheUsage>, AppError> {
+    let max_size = Config::get()
+        .await
+        .ok()
+        .and_then(|c| c.max_clip_cache_size_mb);
+    let usage = state.ffmpeg.cache.usage(max_size)?;
+    Ok(Json(usage))
+}
+
+#[axum::debug_handler]
+pub async fn purge_cache(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<PurgeResult>, AppError> {
+    // jobs of the CLI only show up in the database
+    let jobs = state.jobs.list().await;
+    if jobs.iter().any(|j| !j.status.is_done()) || state.database.has_active_videos()? {
+        return Err(AppError::Conflict(
+            "the clip cache can't be purged while jobs are running".into(),
+        ));
+    }
+
+    let result = state.ffmpeg.cache.purge(&state.ffmpeg.video_dir).await?;
+    Ok(Json(result))
+}
+
+#[axum::debug_handler]
+pub async fn cancel_job(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    state.jobs.cancel(&id).await?;
+    Ok(StatusCode::NO_CONTENT)
 }
 
 #[axum::debug_handler]
@@ -307,6 +909,41 @@ pub async fn download_video(
     Ok((headers, body))
 }
 
+#[derive(Deserialize, Debug)]
+pub struct ChaptersQuery {
+    #[serde(default)]
+    format: ChapterFormat,
+}
+
+#[axum::debug_handler]
+pub async fn download_chapters(
+    state: State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(query): Query<ChaptersQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    use axum::{http::header, response::AppendHeaders};
+
+    let path = state.ffmpeg.chapters_path(&id);
+    if !path.is_file() {
+        return Err(AppError::NotFound(format!("no chapters for video {id}")));
+    }
+    let json = tokio::fs::read_to_string(path).await?;
+    let (content_type, extension, body) = match query.format {
+        ChapterFormat::Json => ("application/json", "json", json),
+        ChapterFormat::Webvtt => {
+            let chapters: Vec<Chapter> =
+                serde_json::from_str(&json).map_err(|e| AppError::Generic(e.into()))?;
+            ("text/vtt", "vtt", chapters::webvtt(&chapters))
+        }
+    };
+    let content_disposition = format!("attachment; filename=\"{id}.chapters.{extension}\"");
+    let headers = AppendHeaders([
+        (header::CONTENT_TYPE, content_type.to_string()),
+        (header::CONTENT_DISPOSITION, content_disposition),
+    ]);
+    Ok((headers, body))
+}
+
 #[axum::debug_handler]
 pub async fn get_config() -> StatusCode {
     match Config::get().await {
@@ -316,9 +953,9 @@ pub async fn get_config() -> StatusCode {
 }
 
 #[axum::debug_handler]
-pub async fn set_config(Json(config): Json<Config>) -> Result<StatusCode, AppError> {
-    tracing::info!("setting config with URL {}", config.stash_url);
-    config::set_config(config).await?;
+pub async fn set_config(Json(settings): Json<StashSettings>) -> Result<StatusCode, AppError> {
+    tracing::info!("setting config with URL {}", settings.stash_url);
+    config::set_stash_settings(settings).await?;
 
     Ok(StatusCode::NO_CONTENT)
 }
@@ -0,0 +1,336 @@
+use std::{
+    error::Error,
+    future::Future,
+    io,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use camino::Utf8Path;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, Semaphore};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    database::Database,
+    error::AppError,
+    ffmpeg::{ClipError, CommandError},
+    http::CreateVideoBody,
+};
+
+/// How long ended jobs are kept in memory. Their history stays in the database.
+const ENDED_JOB_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+/// How many ended jobs are kept in memory at most.
+const MAX_ENDED_JOBS: usize = 50;
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Progress {
+    pub finished: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Finished,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn is_done(&self) -> bool {
+        matches!(
+            self,
+            JobStatus::Finished | JobStatus::Failed | JobStatus::Cancelled
+        )
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Finished => "finished",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl FromStr for JobStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queued" => Ok(JobStatus::Queued),
+            "running" => Ok(JobStatus::Running),
+            "finished" => Ok(JobStatus::Finished),
+            "failed" => Ok(JobStatus::Failed),
+            "cancelled" => Ok(JobStatus::Cancelled),
+            _ => Err(format!("unknown job status {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorKind {
+    Ffmpeg,
+    Io,
+    Stash,
+    Other,
+}
+
+/// Describes why a job failed, in a form that can be shown in the UI.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobError {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub marker_id: Option<String>,
+    pub clip: Option<String>,
+    pub stderr: Option<String>,
+}
+
+impl JobError {
+    pub fn from_error(error: &AppError) -> Self {
+        let mut job_error = JobError {
+            kind: ErrorKind::Other,
+            message: error.to_string(),
+            marker_id: None,
+            clip: None,
+            stderr: None,
+        };
+
+        let mut current: Option<&(dyn Error + 'static)> = match error {
+            AppError::Generic(e) => Some(e.as_ref()),
+            AppError::Io(e) => Some(e),
+            _ => None,
+        };
+        while let Some(e) = current {
+            if let Some(clip_error) = e.downcast_ref::<ClipError>() {
+                job_error.marker_id = Some(clip_error.marker_id.clone());
+                job_error.clip = Some(clip_error.clip.clone());
+            } else if let Some(command_error) = e.downcast_ref::<CommandError>() {
+                job_error.kind = ErrorKind::Ffmpeg;
+                job_error.stderr = Some(command_error.stderr.clone());
+            } else if e.is::<io::Error>() {
+                job_error.kind = ErrorKind::Io;
+            } else if e.is::<reqwest::Error>() {
+                job_error.kind = ErrorKind::Stash;
+            }
+            current = e.source();
+        }
+
+        job_error
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Job {
+    pub id: String,
+    pub status: JobStatus,
+    pub progress: Progress,
+    pub error: Option<JobError>,
+    #[serde(skip)]
+    cancel: CancellationToken,
+    #[serde(skip)]
+    ended_at: Option<Instant>,
+}
+
+type JobList = Arc<Mutex<Vec<Job>>>;
+
+/// Removes ended jobs that are older than the retention window, and the oldest ones
+/// beyond the limit.
+fn prune_ended(jobs: &mut Vec<Job>) {
+    jobs.retain(|j| j.ended_at.is_none_or(|t| t.elapsed() < ENDED_JOB_RETENTION));
+    let mut ended: Vec<_> = jobs.iter().filter_map(|j| j.ended_at).collect();
+    if ended.len() > MAX_ENDED_JOBS {
+        ended.sort_unstable_by(|a, b| b.cmp(a));
+        let oldest_kept = ended[MAX_ENDED_JOBS - 1];
+        jobs.retain(|j| j.ended_at.is_none_or(|t| t >= oldest_kept));
+    }
+}
+
+/// Gives a running job access to its own entry in the queue, so it can report progress.
+#[derive(Clone)]
+pub struct JobHandle {
+    id: String,
+    jobs: JobList,
+    cancel: CancellationToken,
+    database: Arc<Database>,
+}
+
+impl JobHandle {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// Completes once the job has been cancelled.
+    pub async fn cancelled(&self) {
+        self.cancel.cancelled().await
+    }
+
+    /// Fails if the job has been cancelled, so it can stop between stages that don't
+    /// run a command.
+    pub fn check_cancelled(&self) -> crate::Result<()> {
+        if self.is_cancelled() {
+            Err(format!("job {} was cancelled", self.id).into())
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn update(&self, f: impl FnOnce(&mut Job)) {
+        let mut jobs = self.jobs.lock().await;
+        if let Some(job) = jobs.iter_mut().find(|j| j.id == self.id) {
+            f(job);
+        }
+    }
+
+    pub async fn set_total(&self, total: usize) {
+        self.update(|job| job.progress.total = total).await;
+    }
+
+    pub async fn increase_progress(&self) {
+        self.update(|job| job.progress.finished += 1).await;
+    }
+
+    async fn set_status(&self, status: JobStatus, error: Option<JobError>) {
+        if let Err(e) = self.database.set_status(&self.id, status, error.as_ref()) {
+            tracing::warn!("failed to persist status of job {}: {e}", self.id);
+        }
+        let mut jobs = self.jobs.lock().await;
+        if let Some(job) = jobs.iter_mut().find(|j| j.id == self.id) {
+            job.status = status;
+            job.error = error;
+            job.ended_at = status.is_done().then(Instant::now);
+        }
+        prune_ended(&mut jobs);
+    }
+
+    /// Records the finished compilation of this job.
+    pub async fn set_output(&self, path: &Utf8Path) -> crate::Result<()> {
+        let metadata = tokio::fs::metadata(path).await?;
+        self.database.set_output(&self.id, path, metadata.len())
+    }
+}
+
+pub struct JobQueue {
+    jobs: JobList,
+    permits: Arc<Semaphore>,
+    database: Arc<Database>,
+}
+
+impl JobQueue {
+    pub fn new(max_concurrent_jobs: usize, database: Arc<Database>) -> Self {
+        tracing::info!("running at most {max_concurrent_jobs} job(s) at a time");
+        JobQueue {
+            jobs: Default::default(),
+            permits: Arc::new(Semaphore::new(max_concurrent_jobs.max(1))),
+            database,
+        }
+    }
+
+    pub async fn list(&self) -> Vec<Job> {
+        let mut jobs = self.jobs.lock().await;
+        prune_ended(&mut jobs);
+        jobs.clone()
+    }
+
+    pub async fn get(&self, id: &str) -> Option<Job> {
+        let mut jobs = self.jobs.lock().await;
+        prune_ended(&mut jobs);
+        jobs.iter().find(|j| j.id == id).cloned()
+    }
+
+    /// Queues a new job for the given options, using their ID as the job ID. The job starts
+    /// running as soon as a slot becomes available. Jobs that have ended can be submitted again.
+    pub async fn submit<F, Fut>(&self, options: &CreateVideoBody, f: F) -> Result<(), AppError>
+    where
+        F: FnOnce(JobHandle) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), AppError>> + Send + 'static,
+    {
+        let id = options.id.clone();
+        let cancel = CancellationToken::new();
+        {
+            let mut jobs = self.jobs.lock().await;
+            if let Some(existing) = jobs.iter().find(|j| j.id == id) {
+                if !existing.status.is_done() {
+                    return Err(AppError::Conflict(format!("job {id} is already running")));
+                }
+            }
+            self.database.insert_video(options)?;
+            jobs.retain(|j| j.id != id);
+            jobs.push(Job {
+                id: id.clone(),
+                status: JobStatus::Queued,
+                progress: Default::default(),
+                error: None,
+                cancel: cancel.clone(),
+                ended_at: None,
+            });
+            prune_ended(&mut jobs);
+        }
+
+        let handle = JobHandle {
+            id,
+            jobs: self.jobs.clone(),
+            cancel,
+            database: self.database.clone(),
+        };
+        let permits = self.permits.clone();
+        tokio::spawn(async move {
+            let _permit = tokio::select! {
+                permit = permits.acquire_owned() => permit.expect("semaphore is never closed"),
+                _ = handle.cancelled() => {
+                    tracing::info!("job {} was cancelled while queued", handle.id);
+                    handle.set_status(JobStatus::Cancelled, None).await;
+                    return;
+                }
+            };
+            handle.set_status(JobStatus::Running, None).await;
+            tracing::info!("starting job {}", handle.id);
+
+            let (status, error) = match f(handle.clone()).await {
+                Ok(_) => (JobStatus::Finished, None),
+                Err(_) if handle.is_cancelled() => {
+                    tracing::info!("job {} was cancelled", handle.id);
+                    (JobStatus::Cancelled, None)
+                }
+                Err(e) => {
+                    tracing::error!("job {} failed: {e:?}", handle.id);
+                    (JobStatus::Failed, Some(JobError::from_error(&e)))
+                }
+            };
+            handle.set_status(status, error).await;
+        });
+
+        Ok(())
+    }
+
+    /// Requests cancellation of a queued or running job. Running jobs stop at the
+    /// next ffmpeg invocation, which is killed if it is still in progress.
+    pub async fn cancel(&self, id: &str) -> Result<(), AppError> {
+        let jobs = self.jobs.lock().await;
+        match jobs.iter().find(|j| j.id == id) {
+            None => Err(AppError::NotFound(format!("no job with id {id}"))),
+            Some(job) if job.status.is_done() => {
+                Err(AppError::Conflict(format!("job {id} has already ended")))
+            }
+            Some(job) => {
+                tracing::info!("cancelling job {id}");
+                job.cancel.cancel();
+                Ok(())
+            }
+        }
+    }
+}
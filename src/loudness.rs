@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// Loudness range `loudnorm` aims for, unless the audio already has a wider range.
+/// Keeping the measured range lets `loudnorm` use a constant gain.
+const DEFAULT_LOUDNESS_RANGE: f64 = 11.0;
+
+/// Anything quieter than this is treated as silence and left alone.
+const SILENCE_THRESHOLD: f64 = -70.0;
+
+/// Normalizes the loudness of the compilation according to EBU R128.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct LoudnessNormalization {
+    #[serde(default)]
+    pub mode: LoudnessMode,
+    #[serde(flatten)]
+    pub target: LoudnessTarget,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum LoudnessMode {
+    /// Every clip is normalized on its own, so they all sound equally loud. Normalized
+    /// clips are cached separately.
+    PerClip,
+    /// The finished video is normalized as a whole, which keeps the differences
+    /// between clips.
+    #[default]
+    Output,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LoudnessTarget {
+    /// Integrated loudness in LUFS.
+    #[serde(default = "default_integrated")]
+    pub integrated: f64,
+    /// Maximum true peak in dBTP.
+    #[serde(default = "default_true_peak")]
+    pub true_peak: f64,
+}
+
+fn default_integrated() -> f64 {
+    -16.0
+}
+
+fn default_true_peak() -> f64 {
+    -1.5
+}
+
+impl Default for LoudnessTarget {
+    fn default() -> Self {
+        LoudnessTarget {
+            integrated: default_integrated(),
+            true_peak: default_true_peak(),
+        }
+    }
+}
+
+impl LoudnessTarget {
+    pub fn validate(&self) -> Result<()> {
+        if !(-70.0..=-5.0).contains(&self.integrated) {
+            return Err("the target loudness must be between -70 and -5 LUFS".into());
+        }
+        if !(-9.0..=0.0).contains(&self.true_peak) {
+            return Err("the true peak limit must be between -9 and 0 dBTP".into());
+        }
+        Ok(())
+    }
+
+    /// Filter for the first pass, which only measures the audio.
+    pub fn measure_filter(&self) -> String {
+        format!(
+            "loudnorm=I={}:TP={}:LRA={DEFAULT_LOUDNESS_RANGE}:print_format=json",
+            self.integrated, self.true_peak
+        )
+    }
+
+    /// Filter for the second pass, using the values measured in the first one. Returns
+    /// `None` for silent audio, which can't be normalized.
+    pub fn normalize_filter(&self, measured: &LoudnessMeasurement) -> Option<String> {
+        if !measured.input_i.is_finite() || measured.input_i < SILENCE_THRESHOLD {
+            return None;
+        }
+        let range = measured.input_lra.clamp(DEFAULT_LOUDNESS_RANGE, 50.0);
+        Some(format!(
+            "loudnorm=I={}:TP={}:LRA={range}:measured_I={}:measured_TP={}:\
+             measured_LRA={}:measured_thresh={}:offset={}:linear=true",
+            self.integrated,
+            self.true_peak,
+            measured.input_i,
+            measured.input_tp,
+            measured.input_lra,
+            measured.input_thresh,
+            measured.target_offset,
+        ))
+    }
+}
+
+/// What `loudnorm` measured in the first pass.
+#[derive(Debug, Clone, Copy)]
+pub struct LoudnessMeasurement {
+    pub input_i: f64,
+    pub input_tp: f64,
+    pub input_lra: f64,
+    pub input_thresh: f64,
+    pub target_offset: f64,
+}
+
+/// Reads the measurement from the JSON that `loudnorm` prints at the end of ffmpeg's output.
+pub fn parse_measurement(stderr: &str) -> Result<LoudnessMeasurement> {
+    #[derive(Deserialize)]
+    struct LoudnormJson {
+        input_i: String,
+        input_tp: String,
+        input_lra: String,
+        input_thresh: String,
+        target_offset: String,
+    }
+
+    let start = stderr
+        .rfind('{')
+        .ok_or("ffmpeg did not print a loudness measurement")?;
+    let end = stderr[start..]
+        .find('}')
+        .ok_or("ffmpeg printed an incomplete loudness measurement")?;
+    let json: LoudnormJson = serde_json::from_str(&stderr[start..=start + end])?;
+    Ok(LoudnessMeasurement {
+        input_i: json.input_i.parse()?,
+        input_tp: json.input_tp.parse()?,
+        input_lra: json.input_lra.parse()?,
+        input_thresh: json.input_thresh.parse()?,
+        target_offset: json.target_offset.parse()?,
+    })
+}
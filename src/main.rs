@@ -1,50 +1,87 @@
-use crate::ffmpeg::Ffmpeg;
+use crate::{
+    cli::{Cli, Command},
+    config::Config,
+    database::Database,
+    ffmpeg::Ffmpeg,
+    jobs::JobQueue,
+};
 use axum::{
     routing::{get, post},
     Router,
 };
-use std::{sync::Arc, time::Duration};
+use clap::Parser;
+use std::{process::ExitCode, sync::Arc, time::Duration};
 
+mod audio;
+mod beats;
+mod chapters;
+mod cli;
+mod clip_cache;
+mod clips;
 mod config;
+mod database;
 mod download_ffmpeg;
+mod encoding;
 mod error;
 mod ffmpeg;
+mod filters;
 mod http;
+mod jobs;
+mod loudness;
+mod overlays;
+mod performers;
+mod recipe;
+mod saved_filters;
 mod stash_api;
 mod static_files;
 
-pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
 pub struct AppState {
     pub ffmpeg: Ffmpeg,
+    pub jobs: Arc<JobQueue>,
+    pub database: Arc<Database>,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    use std::env;
-    use tracing_subscriber::{fmt, prelude::*, EnvFilter};
-
-    if env::var("RUST_LOG").is_err() {
-        env::set_var("RUST_LOG", "info");
-    }
-
-    tracing_subscriber::registry()
-        .with(fmt::layer())
-        .with(EnvFilter::from_default_env())
-        .init();
-
-    config::init().await;
+async fn create_state() -> Result<Arc<AppState>> {
+    let database = Arc::new(Database::new()?);
+    let ffmpeg = Ffmpeg::new(database.clone()).await?;
+    let max_concurrent_jobs = Config::get()
+        .await
+        .map(|c| c.max_concurrent_jobs)
+        .unwrap_or(1);
+    let jobs = Arc::new(JobQueue::new(max_concurrent_jobs, database.clone()));
+    Ok(Arc::new(AppState {
+        ffmpeg,
+        jobs,
+        database,
+    }))
+}
 
-    let ffmpeg = Ffmpeg::new().await?;
-    let state = Arc::new(AppState { ffmpeg });
+async fn serve(state: Arc<AppState>) -> Result<()> {
+    state.database.fail_interrupted_videos()?;
 
     let app = Router::new()
         .route("/api/tags", get(http::fetch_tags))
         .route("/api/performers", get(http::fetch_performers))
-        .route("/api/markers", get(http::fetch_markers))
+        .route("/api/studios", get(http::fetch_studios))
+        .route("/api/scenes", post(http::search_scenes))
+        .route("/api/saved-filters", get(http::fetch_saved_filters))
+        .route(
+            "/api/markers",
+            get(http::fetch_markers).post(http::search_markers),
+        )
         .route("/api/create", post(http::create_video))
-        .route("/api/progress", get(http::get_progress))
+        .route("/api/progress/:id", get(http::get_progress))
+        .route("/api/jobs", get(http::list_jobs))
+        .route("/api/jobs/:id", get(http::get_job).delete(http::cancel_job))
+        .route("/api/videos", get(http::list_videos))
+        .route("/api/videos/:id/rerun", post(http::rerun_video))
+        .route("/api/cache", get(http::get_cache_usage))
+        .route("/api/cache/purge", post(http::purge_cache))
         .route("/api/download/:id", get(http::download_video))
+        .route("/api/download/:id/chapters", get(http::download_chapters))
+        .route("/api/encoding-profiles", get(http::list_encoding_profiles))
         .route("/api/config", get(http::get_config))
         .route("/api/config", post(http::set_config))
         .fallback_service(static_files::service())
@@ -68,3 +105,35 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::main]
+async fn main() -> Result<ExitCode> {
+    use std::env;
+    use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Serve);
+
+    if env::var("RUST_LOG").is_err() {
+        // keep the terminal free for the progress output of headless commands
+        let level = if command.is_headless() {
+            "warn"
+        } else {
+            "info"
+        };
+        env::set_var("RUST_LOG", level);
+    }
+
+    tracing_subscriber::registry()
+        .with(fmt::layer())
+        .with(EnvFilter::from_default_env())
+        .init();
+
+    config::init().await;
+    let state = create_state().await?;
+
+    match command {
+        Command::Serve => serve(state).await.map(|_| ExitCode::SUCCESS),
+        command => Ok(cli::run(state, command).await),
+    }
+}
@@ -0,0 +1,252 @@
+use camino::Utf8Path;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ffmpeg::{performer_names, scene_title},
+    performers::Gender,
+    stash_api::find_markers_query::FindMarkersQueryFindSceneMarkersSceneMarkers as Marker,
+    Result,
+};
+
+/// Text drawn onto the compilation with `drawtext`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Overlays {
+    /// Path to a font file, or the name of a font that is looked up with fontconfig.
+    #[serde(default = "default_font")]
+    pub font: String,
+    /// Card shown before the first clip.
+    #[serde(default)]
+    pub intro: Option<TitleCard>,
+    /// Text shown at the start of every clip.
+    #[serde(default)]
+    pub lower_third: Option<LowerThird>,
+    /// Card shown after the last clip.
+    #[serde(default)]
+    pub outro: Option<TitleCard>,
+}
+
+fn default_font() -> String {
+    "Sans".into()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TitleCard {
+    pub text: String,
+    /// In seconds.
+    #[serde(default = "default_card_duration")]
+    pub duration: f64,
+    #[serde(default)]
+    pub position: TextPosition,
+    /// Font size in pixels, defaults to a twelfth of the video's height.
+    #[serde(default)]
+    pub size: Option<u32>,
+}
+
+fn default_card_duration() -> f64 {
+    3.0
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LowerThird {
+    /// What is shown, in this order.
+    #[serde(default = "default_fields")]
+    pub fields: Vec<OverlayField>,
+    /// How long the text is shown at the start of every clip, in seconds.
+    #[serde(default = "default_lower_third_duration")]
+    pub duration: f64,
+    #[serde(default = "default_lower_third_position")]
+    pub position: TextPosition,
+    /// Font size in pixels, defaults to a twenty-fourth of the video's height.
+    #[serde(default)]
+    pub size: Option<u32>,
+}
+
+fn default_fields() -> Vec<OverlayField> {
+    vec![OverlayField::SceneTitle, OverlayField::Performers]
+}
+
+fn default_lower_third_duration() -> f64 {
+    4.0
+}
+
+fn default_lower_third_position() -> TextPosition {
+    TextPosition::BottomLeft
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverlayField {
+    SceneTitle,
+    Performers,
+    Studio,
+    Tag,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum TextPosition {
+    TopLeft,
+    Top,
+    TopRight,
+    #[default]
+    Center,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl TextPosition {
+    /// The `x` and `y` expressions for `drawtext`, keeping a margin to the edges.
+    fn coordinates(&self) -> (&'static str, &'static str) {
+        const LEFT: &str = "h/20";
+        const CENTER_X: &str = "(w-text_w)/2";
+        const RIGHT: &str = "w-text_w-h/20";
+        const TOP: &str = "h/20";
+        const CENTER_Y: &str = "(h-text_h)/2";
+        const BOTTOM: &str = "h-text_h-h/20";
+
+        match self {
+            TextPosition::TopLeft => (LEFT, TOP),
+            TextPosition::Top => (CENTER_X, TOP),
+            TextPosition::TopRight => (RIGHT, TOP),
+            TextPosition::Center => (CENTER_X, CENTER_Y),
+            TextPosition::BottomLeft => (LEFT, BOTTOM),
+            TextPosition::Bottom => (CENTER_X, BOTTOM),
+            TextPosition::BottomRight => (RIGHT, BOTTOM),
+        }
+    }
+}
+
+/// A piece of text to draw, with everything needed to build the filter. It is part of
+/// the clip parameters, so clips with different text are cached separately.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TextOverlay {
+    pub text: String,
+    pub font: String,
+    pub size: u32,
+    pub position: TextPosition,
+    /// Only draws the text for this many seconds from the start, if set.
+    pub duration: Option<f64>,
+    /// Draws a translucent box behind the text.
+    pub boxed: bool,
+}
+
+/// Escapes a value for a filter option, and then for the filter graph it is part of.
+fn escape(value: &str) -> String {
+    let mut option = String::new();
+    for c in value.chars() {
+        if matches!(c, '\\' | '\'' | ':') {
+            option.push('\\');
+        }
+        option.push(c);
+    }
+    let mut graph = String::new();
+    for c in option.chars() {
+        if matches!(c, '\\' | '\'' | '[' | ']' | ',' | ';') {
+            graph.push('\\');
+        }
+        graph.push(c);
+    }
+    graph
+}
+
+impl TextOverlay {
+    pub fn filter(&self) -> String {
+        let font = if is_font_file(&self.font) {
+            format!("fontfile={}", escape(&self.font))
+        } else {
+            format!("font={}", escape(&self.font))
+        };
+        let (x, y) = self.position.coordinates();
+        let mut filter = format!(
+            "drawtext={font}:expansion=none:text={}:fontsize={}:fontcolor=white:x={x}:y={y}",
+            escape(&self.text),
+            self.size
+        );
+        if self.boxed {
+            filter.push_str(":box=1:boxcolor=black@0.5:boxborderw=10");
+        } else {
+            filter.push_str(":shadowx=2:shadowy=2");
+        }
+        if let Some(duration) = self.duration {
+            filter.push_str(&format!(":enable=lt(t\\,{duration})"));
+        }
+        filter
+    }
+}
+
+fn is_font_file(font: &str) -> bool {
+    font.contains('/') || font.contains('\\') || Utf8Path::new(font).extension().is_some()
+}
+
+impl Overlays {
+    pub fn validate(&self) -> Result<()> {
+        if is_font_file(&self.font) && !Utf8Path::new(&self.font).is_file() {
+            return Err(format!("font file {} does not exist", self.font).into());
+        }
+        let durations = [
+            self.intro.as_ref().map(|c| c.duration),
+            self.lower_third.as_ref().map(|l| l.duration),
+            self.outro.as_ref().map(|c| c.duration),
+        ];
+        if durations.into_iter().flatten().any(|d| d <= 0.0) {
+            return Err("overlay durations must be positive".into());
+        }
+        Ok(())
+    }
+
+    /// The overlay for a title card, for a video of the given height.
+    pub fn card(&self, card: &TitleCard, height: u32) -> TextOverlay {
+        TextOverlay {
+            text: card.text.clone(),
+            font: self.font.clone(),
+            size: card.size.unwrap_or(height / 12),
+            position: card.position,
+            duration: None,
+            boxed: false,
+        }
+    }
+
+    /// The lower third for clips from this marker, if there is anything to show. Only
+    /// performers with one of the given genders are named.
+    pub fn lower_third(
+        &self,
+        marker: &Marker,
+        height: u32,
+        genders: &[Gender],
+    ) -> Option<TextOverlay> {
+        let lower_third = self.lower_third.as_ref()?;
+        let parts: Vec<String> = lower_third
+            .fields
+            .iter()
+            .filter_map(|field| match field {
+                OverlayField::SceneTitle => Some(scene_title(marker).to_string()),
+                OverlayField::Performers => {
+                    Some(performer_names(marker, genders).join(", ")).filter(|p| !p.is_empty())
+                }
+                OverlayField::Studio => marker.scene.studio.as_ref().map(|s| s.name.clone()),
+                OverlayField::Tag => Some(marker.primary_tag.name.clone()),
+            })
+            .collect();
+        if parts.is_empty() {
+            return None;
+        }
+
+        Some(TextOverlay {
+            text: parts.join(" - "),
+            font: self.font.clone(),
+            size: lower_third.size.unwrap_or(height / 24),
+            position: lower_third.position,
+            duration: Some(lower_third.duration),
+            boxed: true,
+        })
+    }
+
+    pub fn intro_duration(&self) -> f64 {
+        self.intro.as_ref().map_or(0.0, |c| c.duration)
+    }
+}
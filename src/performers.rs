@@ -0,0 +1,62 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    stash_api::{find_markers_query, find_performers_query},
+    Result,
+};
+
+/// A performer's gender, as Stash names it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Gender {
+    Male,
+    Female,
+    TransgenderMale,
+    TransgenderFemale,
+    Intersex,
+    NonBinary,
+}
+
+/// Converts the gender enum that is generated for every query.
+macro_rules! from_gender_enum {
+    ($($module:ident),*) => {$(
+        impl From<&$module::GenderEnum> for Option<Gender> {
+            fn from(gender: &$module::GenderEnum) -> Self {
+                use $module::GenderEnum;
+
+                match gender {
+                    GenderEnum::MALE => Some(Gender::Male),
+                    GenderEnum::FEMALE => Some(Gender::Female),
+                    GenderEnum::TRANSGENDER_MALE => Some(Gender::TransgenderMale),
+                    GenderEnum::TRANSGENDER_FEMALE => Some(Gender::TransgenderFemale),
+                    GenderEnum::INTERSEX => Some(Gender::Intersex),
+                    GenderEnum::NON_BINARY => Some(Gender::NonBinary),
+                    GenderEnum::Other(_) => None,
+                }
+            }
+        }
+    )*};
+}
+
+from_gender_enum!(find_markers_query, find_performers_query);
+
+/// Whether performers of the given gender are shown, in the performer list and in
+/// scene labels. An empty list shows everyone, and performers without a gender are
+/// always shown.
+pub fn shows_gender(genders: &[Gender], gender: Option<Gender>) -> bool {
+    match gender {
+        Some(gender) => genders.is_empty() || genders.contains(&gender),
+        None => true,
+    }
+}
+
+/// Parses a comma-separated list of genders as Stash names them, like
+/// `FEMALE,NON_BINARY`.
+pub fn parse_genders(list: &str) -> Result<Vec<Gender>> {
+    list.split(',')
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            serde_json::from_value(name.into()).map_err(|_| format!("unknown gender {name}").into())
+        })
+        .collect()
+}
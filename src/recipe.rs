@@ -0,0 +1,174 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clips::{ClipOrder, ClipStrategy},
+    http::{self, CreateVideoBody, Resolution},
+    stash_api::Api,
+    Result,
+};
+
+/// The current version of the recipe format. Bump this whenever a change to
+/// `CreateVideoBody` would make older recipes unreadable.
+///
+/// Version 1 contained the selected markers as Stash returned them. Version 2 only
+/// keeps their IDs, and the markers in version 1 recipes are ignored when loading.
+pub const RECIPE_VERSION: u32 = 2;
+
+/// Everything needed to render a compilation again.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Recipe {
+    pub version: u32,
+    /// Markers that matched the filter, but were deselected. They stay excluded when
+    /// the recipe is rendered again, while markers added to Stash later are picked up.
+    pub excluded_markers: Vec<String>,
+    /// The options without markers, which are fetched again when rendering.
+    #[serde(with = "http::stored_options")]
+    pub options: CreateVideoBody,
+}
+
+/// Settings that can be changed when rendering a recipe again.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecipeOverrides {
+    pub output_resolution: Option<Resolution>,
+    pub output_fps: Option<u32>,
+    pub clip_order: Option<ClipOrder>,
+    pub order_seed: Option<u64>,
+    pub clip_strategy: Option<ClipStrategy>,
+    pub encoding_profile: Option<String>,
+}
+
+pub fn recipe_path(video_dir: &Utf8Path, id: &str) -> Utf8PathBuf {
+    video_dir.join(format!("{id}.recipe.json"))
+}
+
+pub fn generate_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(8)
+        .map(char::from)
+        .collect()
+}
+
+impl Recipe {
+    pub fn new(options: &CreateVideoBody) -> Self {
+        let excluded_markers = options
+            .markers
+            .iter()
+            .filter(|m| !options.selected_markers.contains(&m.id))
+            .map(|m| m.id.clone())
+            .collect();
+
+        Recipe {
+            version: RECIPE_VERSION,
+            excluded_markers,
+            options: options.clone(),
+        }
+    }
+
+    pub async fn load(path: &Utf8Path) -> Result<Self> {
+        let text = tokio::fs::read_to_string(path).await?;
+        let recipe: Recipe =
+            serde_json::from_str(&text).map_err(|e| format!("recipe {path} can't be read: {e}"))?;
+        if recipe.version > RECIPE_VERSION {
+            return Err(format!(
+                "recipe {path} has version {}, but only versions up to {RECIPE_VERSION} are supported",
+                recipe.version
+            )
+            .into());
+        }
+        Ok(recipe)
+    }
+
+    pub async fn save(&self, video_dir: &Utf8Path) -> Result<()> {
+        tokio::fs::create_dir_all(video_dir).await?;
+        let path = recipe_path(video_dir, &self.options.id);
+        let contents = serde_json::to_string_pretty(self)?;
+        tokio::fs::write(&path, contents).await?;
+        tracing::info!("saved recipe to {path}");
+        Ok(())
+    }
+
+    /// Builds the options for a new video from this recipe, fetching the current
+    /// markers from Stash.
+    pub async fn to_options(
+        &self,
+        api: &Api,
+        overrides: &RecipeOverrides,
+    ) -> Result<CreateVideoBody> {
+        let options = &self.options;
+        let mut markers =
+            http::query_markers(api, &options.marker_filter(), options.scene_filter.as_ref())
+                .await?;
+        markers.retain(|m| !self.excluded_markers.contains(&m.id));
+        tracing::info!(
+            "found {} markers for recipe {}, previously {}",
+            markers.len(),
+            options.id,
+            options.selected_markers.len()
+        );
+
+        Ok(CreateVideoBody {
+            id: generate_id(),
+            selected_markers: markers.iter().map(|m| m.id.clone()).collect(),
+            markers,
+            output_resolution: overrides
+                .output_resolution
+                .unwrap_or(options.output_resolution),
+            output_fps: overrides.output_fps.unwrap_or(options.output_fps),
+            clip_order: overrides.clip_order.unwrap_or(options.clip_order),
+            order_seed: overrides.order_seed.or(options.order_seed),
+            clip_strategy: overrides.clip_strategy.unwrap_or(options.clip_strategy),
+            encoding_profile: overrides
+                .encoding_profile
+                .clone()
+                .or_else(|| options.encoding_profile.clone()),
+            ..options.clone()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn options() -> serde_json::Value {
+        json!({
+            "selectMode": "tags",
+            "selectedIds": ["1"],
+            "clipOrder": "random",
+            "clipDuration": 10,
+            "outputResolution": "720",
+            "outputFps": 30,
+            "selectedMarkers": ["5"],
+            "markers": [],
+            "id": "abcd1234",
+        })
+    }
+
+    #[test]
+    fn saves_only_marker_ids() {
+        let options: CreateVideoBody = serde_json::from_value(options()).unwrap();
+        let recipe = serde_json::to_value(Recipe::new(&options)).unwrap();
+        assert_eq!(recipe["version"], RECIPE_VERSION);
+        assert!(recipe["options"].get("markers").is_none());
+        assert_eq!(recipe["options"]["selectedMarkers"], json!(["5"]));
+    }
+
+    #[test]
+    fn reads_version_1_recipes() {
+        // the markers of version 1 are missing fields that are required now
+        let mut options = options();
+        options["markers"] = json!([{"id": "5", "seconds": 12.0}]);
+        let recipe = json!({"version": 1, "excludedMarkers": ["6"], "options": options});
+        let recipe: Recipe = serde_json::from_value(recipe).unwrap();
+        assert_eq!(recipe.excluded_markers, vec!["6"]);
+        assert!(recipe.options.markers.is_empty());
+        assert_eq!(recipe.options.selected_markers, vec!["5"]);
+    }
+}
@@ -0,0 +1,329 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    filters::Exclusions,
+    stash_api::find_markers_query::{
+        CriterionModifier, DateCriterionInput, FindFilterType, HierarchicalMultiCriterionInput,
+        MultiCriterionInput, SceneMarkerFilterType, SortDirectionEnum, TimestampCriterionInput,
+    },
+    Result,
+};
+
+/// A saved scene marker filter from Stash, translated into the variables of a marker
+/// query.
+pub struct SavedMarkerQuery {
+    pub filter: FindFilterType,
+    pub scene_marker_filter: SceneMarkerFilterType,
+    /// Excluded IDs can't be sent to Stash in a criterion, so they are applied to the
+    /// results instead.
+    pub exclusions: Exclusions,
+}
+
+/// The filter the way the Stash UI saves it.
+#[derive(Deserialize)]
+struct SavedFilterJson {
+    #[serde(default)]
+    q: Option<String>,
+    #[serde(default)]
+    sortby: Option<String>,
+    #[serde(default)]
+    sortdir: Option<String>,
+    /// The criteria, every one of them encoded as JSON again.
+    #[serde(default)]
+    c: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct SavedCriterion {
+    #[serde(rename = "type")]
+    kind: String,
+    modifier: CriterionModifier,
+    #[serde(default)]
+    value: Value,
+}
+
+#[derive(Deserialize)]
+struct Item {
+    id: String,
+}
+
+/// The IDs of a criterion. Older Stash versions save a plain list, newer ones an
+/// object that can also hold excluded IDs and the tag depth.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ItemsValue {
+    Items {
+        #[serde(default)]
+        items: Vec<Item>,
+        #[serde(default)]
+        excluded: Vec<Item>,
+        #[serde(default)]
+        depth: Option<i64>,
+    },
+    List(Vec<Item>),
+}
+
+#[derive(Deserialize, Default)]
+struct RangeValue {
+    #[serde(default)]
+    value: Option<String>,
+    #[serde(default)]
+    value2: Option<String>,
+}
+
+#[derive(Default)]
+struct Ids {
+    included: Vec<String>,
+    excluded: Vec<String>,
+    depth: Option<i64>,
+}
+
+fn ids(value: Value) -> Result<Ids> {
+    fn ids(items: Vec<Item>) -> Vec<String> {
+        items.into_iter().map(|i| i.id).collect()
+    }
+
+    // criteria like "is null" don't have a value
+    if value.is_null() {
+        return Ok(Ids::default());
+    }
+    Ok(match serde_json::from_value(value)? {
+        ItemsValue::Items {
+            items,
+            excluded,
+            depth,
+        } => Ids {
+            included: ids(items),
+            excluded: ids(excluded),
+            depth,
+        },
+        ItemsValue::List(items) => Ids {
+            included: ids(items),
+            ..Default::default()
+        },
+    })
+}
+
+fn range(value: Value) -> Result<(String, Option<String>)> {
+    let range: RangeValue = if value.is_null() {
+        Default::default()
+    } else {
+        serde_json::from_value(value)?
+    };
+    Ok((range.value.unwrap_or_default(), range.value2))
+}
+
+/// Whether the criterion does anything with the given IDs. An empty list only
+/// matters for modifiers that don't look at the IDs.
+fn is_set(modifier: &CriterionModifier, ids: &Ids) -> bool {
+    !ids.included.is_empty()
+        || matches!(
+            modifier,
+            CriterionModifier::IS_NULL | CriterionModifier::NOT_NULL
+        )
+}
+
+/// Translates the JSON of a saved filter. Fails for criteria that the marker query
+/// doesn't support, instead of silently matching more markers than the filter would.
+pub fn translate(json: &str) -> Result<SavedMarkerQuery> {
+    let saved: SavedFilterJson = serde_json::from_str(json)?;
+    let mut filter = SceneMarkerFilterType {
+        created_at: None,
+        scene_created_at: None,
+        scene_updated_at: None,
+        updated_at: None,
+        performers: None,
+        scene_date: None,
+        scene_tags: None,
+        tag_id: None,
+        tags: None,
+    };
+    let mut exclusions = Exclusions::default();
+
+    for criterion in &saved.c {
+        let SavedCriterion {
+            kind,
+            modifier,
+            value,
+        } = serde_json::from_str(criterion)?;
+        match kind.as_str() {
+            "tags" | "sceneTags" | "scene_tags" => {
+                let ids = ids(value)?;
+                let input = is_set(&modifier, &ids).then_some(HierarchicalMultiCriterionInput {
+                    depth: ids.depth,
+                    modifier,
+                    value: Some(ids.included),
+                });
+                if kind == "tags" {
+                    filter.tags = input;
+                    exclusions.tags = ids.excluded;
+                } else {
+                    filter.scene_tags = input;
+                    exclusions.scene_tags = ids.excluded;
+                }
+            }
+            "performers" => {
+                let ids = ids(value)?;
+                filter.performers = is_set(&modifier, &ids).then_some(MultiCriterionInput {
+                    modifier,
+                    value: Some(ids.included),
+                });
+                exclusions.performers = ids.excluded;
+            }
+            "scene_date" => {
+                let (value, value2) = range(value)?;
+                filter.scene_date = Some(DateCriterionInput {
+                    value,
+                    value2,
+                    modifier,
+                });
+            }
+            "created_at" | "updated_at" | "scene_created_at" | "scene_updated_at" => {
+                let (value, value2) = range(value)?;
+                let input = Some(TimestampCriterionInput {
+                    value,
+                    value2,
+                    modifier,
+                });
+                match kind.as_str() {
+                    "created_at" => filter.created_at = input,
+                    "updated_at" => filter.updated_at = input,
+                    "scene_created_at" => filter.scene_created_at = input,
+                    _ => filter.scene_updated_at = input,
+                }
+            }
+            other => {
+                return Err(format!(
+                    "the saved filter uses the criterion '{other}', which is not supported"
+                )
+                .into())
+            }
+        }
+    }
+
+    let direction = saved.sortdir.map(|d| {
+        if d.eq_ignore_ascii_case("desc") {
+            SortDirectionEnum::DESC
+        } else {
+            SortDirectionEnum::ASC
+        }
+    });
+    Ok(SavedMarkerQuery {
+        filter: FindFilterType {
+            per_page: Some(-1),
+            page: None,
+            q: saved.q.filter(|q| !q.is_empty()),
+            sort: saved.sortby,
+            direction,
+        },
+        scene_marker_filter: filter,
+        exclusions,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn saved_filter(criteria: &[Value]) -> String {
+        let c: Vec<String> = criteria.iter().map(|c| c.to_string()).collect();
+        json!({"q": "", "sortby": "seconds", "sortdir": "desc", "c": c}).to_string()
+    }
+
+    #[test]
+    fn translates_items_with_excludes_and_depth() {
+        let json = saved_filter(&[json!({
+            "type": "tags",
+            "modifier": "INCLUDES",
+            "value": {
+                "items": [{"id": "1", "label": "One"}, {"id": "2", "label": "Two"}],
+                "excluded": [{"id": "3", "label": "Three"}],
+                "depth": -1,
+            },
+        })]);
+        let query = translate(&json).unwrap();
+        assert_eq!(
+            serde_json::to_value(&query.scene_marker_filter.tags).unwrap(),
+            json!({"value": ["1", "2"], "modifier": "INCLUDES", "depth": -1})
+        );
+        assert_eq!(query.exclusions.tags, vec!["3"]);
+        assert!(query.scene_marker_filter.scene_tags.is_none());
+
+        let filter = serde_json::to_value(&query.filter).unwrap();
+        assert_eq!(filter["q"], Value::Null);
+        assert_eq!(filter["sort"], "seconds");
+        assert_eq!(filter["direction"], "DESC");
+        assert_eq!(filter["per_page"], -1);
+    }
+
+    #[test]
+    fn translates_both_scene_tag_names() {
+        for kind in ["sceneTags", "scene_tags"] {
+            let json = saved_filter(&[json!({
+                "type": kind,
+                "modifier": "INCLUDES_ALL",
+                "value": {"items": [{"id": "5"}], "excluded": [{"id": "6"}]},
+            })]);
+            let query = translate(&json).unwrap();
+            assert_eq!(
+                serde_json::to_value(&query.scene_marker_filter.scene_tags).unwrap(),
+                json!({"value": ["5"], "modifier": "INCLUDES_ALL", "depth": null})
+            );
+            assert_eq!(query.exclusions.scene_tags, vec!["6"]);
+            assert!(query.scene_marker_filter.tags.is_none());
+        }
+    }
+
+    #[test]
+    fn translates_the_old_list_format() {
+        let json = saved_filter(&[json!({
+            "type": "performers",
+            "modifier": "INCLUDES",
+            "value": [{"id": "7", "label": "Someone"}],
+        })]);
+        let query = translate(&json).unwrap();
+        assert_eq!(
+            serde_json::to_value(&query.scene_marker_filter.performers).unwrap(),
+            json!({"value": ["7"], "modifier": "INCLUDES"})
+        );
+        assert!(query.exclusions.is_empty());
+    }
+
+    #[test]
+    fn keeps_criteria_without_ids_only_for_null_checks() {
+        let json = saved_filter(&[
+            json!({"type": "performers", "modifier": "IS_NULL"}),
+            json!({"type": "tags", "modifier": "INCLUDES", "value": {"items": []}}),
+        ]);
+        let query = translate(&json).unwrap();
+        assert!(query.scene_marker_filter.performers.is_some());
+        assert!(query.scene_marker_filter.tags.is_none());
+    }
+
+    #[test]
+    fn translates_date_ranges() {
+        let json = saved_filter(&[json!({
+            "type": "scene_date",
+            "modifier": "BETWEEN",
+            "value": {"value": "2020-01-01", "value2": "2020-12-31"},
+        })]);
+        let query = translate(&json).unwrap();
+        assert_eq!(
+            serde_json::to_value(&query.scene_marker_filter.scene_date).unwrap(),
+            json!({"value": "2020-01-01", "value2": "2020-12-31", "modifier": "BETWEEN"})
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_criteria() {
+        let json = saved_filter(&[json!({
+            "type": "rating",
+            "modifier": "GREATER_THAN",
+            "value": {"value": 60},
+        })]);
+        assert!(translate(&json).is_err());
+    }
+}
@@ -1,10 +1,15 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
 use crate::{config::Config, Result};
 use graphql_client::{GraphQLQuery, Response};
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
 
 use self::{
     find_markers_query::FindMarkersQueryFindSceneMarkersSceneMarkers,
     find_performers_query::FindPerformersQueryFindPerformersPerformers,
+    find_saved_filters_query::FindSavedFiltersQueryFindSavedFilters,
+    find_scenes_query::FindScenesQueryFindScenesScenes,
+    find_studios_query::FindStudiosQueryFindStudiosStudios,
     find_tags_query::FindTagsQueryFindTagsTags,
 };
 
@@ -24,6 +29,19 @@ pub struct FindTagsQuery;
 )]
 pub struct FindMarkersQuery;
 
+/// The marker query for Stash versions before marker end times were added.
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "graphql/schema.json",
+    query_path = "graphql/find_markers_legacy.graphql",
+    variables_derives = "Deserialize",
+    response_derives = "Debug"
+)]
+pub struct FindMarkersLegacyQuery;
+
+/// Set once Stash has rejected the `end_seconds` field of markers.
+static LEGACY_MARKERS: AtomicBool = AtomicBool::new(false);
+
 #[derive(GraphQLQuery)]
 #[graphql(
     schema_path = "graphql/schema.json",
@@ -32,6 +50,30 @@ pub struct FindMarkersQuery;
 )]
 pub struct FindPerformersQuery;
 
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "graphql/schema.json",
+    query_path = "graphql/find_scenes.graphql",
+    response_derives = "Debug"
+)]
+pub struct FindScenesQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "graphql/schema.json",
+    query_path = "graphql/find_studios.graphql",
+    response_derives = "Debug"
+)]
+pub struct FindStudiosQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "graphql/schema.json",
+    query_path = "graphql/find_saved_filters.graphql",
+    response_derives = "Debug"
+)]
+pub struct FindSavedFiltersQuery;
+
 pub struct Api {
     api_url: String,
     api_key: String,
@@ -82,8 +124,44 @@ impl Api {
         &self,
         variables: find_markers_query::Variables,
     ) -> Result<Vec<FindMarkersQueryFindSceneMarkersSceneMarkers>> {
+        if LEGACY_MARKERS.load(Ordering::Relaxed) {
+            return self.find_markers_legacy(variables).await;
+        }
+
         let request_body = FindMarkersQuery::build_query(variables);
         let url = format!("{}/graphql", self.api_url);
+        let response = self
+            .client
+            .post(&url)
+            .json(&request_body)
+            .header("ApiKey", &self.api_key)
+            .send()
+            .await?;
+        if response.status() == StatusCode::UNPROCESSABLE_ENTITY {
+            let message = response.text().await?;
+            if message.contains("end_seconds") {
+                tracing::info!("stash doesn't know marker end times, querying without them");
+                LEGACY_MARKERS.store(true, Ordering::Relaxed);
+                return self.find_markers_legacy(request_body.variables).await;
+            }
+            return Err(format!("stash rejected the marker query: {message}").into());
+        }
+        let response = response.error_for_status()?;
+
+        let response: Response<find_markers_query::ResponseData> = response.json().await?;
+        let markers = response.data.unwrap();
+        Ok(markers.find_scene_markers.scene_markers)
+    }
+
+    /// Queries markers without their end times. Both queries take the same variables,
+    /// and the response only lacks `end_seconds`, which is optional anyway.
+    async fn find_markers_legacy(
+        &self,
+        variables: find_markers_query::Variables,
+    ) -> Result<Vec<FindMarkersQueryFindSceneMarkersSceneMarkers>> {
+        let variables = serde_json::from_value(serde_json::to_value(variables)?)?;
+        let request_body = FindMarkersLegacyQuery::build_query(variables);
+        let url = format!("{}/graphql", self.api_url);
         let response = self
             .client
             .post(url)
@@ -117,4 +195,60 @@ impl Api {
         let performers = response.data.unwrap();
         Ok(performers.find_performers.performers)
     }
+
+    pub async fn find_scenes(
+        &self,
+        variables: find_scenes_query::Variables,
+    ) -> Result<Vec<FindScenesQueryFindScenesScenes>> {
+        let request_body = FindScenesQuery::build_query(variables);
+        let url = format!("{}/graphql", self.api_url);
+        let response = self
+            .client
+            .post(url)
+            .json(&request_body)
+            .header("ApiKey", &self.api_key)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let response: Response<find_scenes_query::ResponseData> = response.json().await?;
+        let scenes = response.data.unwrap();
+        Ok(scenes.find_scenes.scenes)
+    }
+
+    pub async fn find_studios(&self) -> Result<Vec<FindStudiosQueryFindStudiosStudios>> {
+        let request_body = FindStudiosQuery::build_query(find_studios_query::Variables {});
+        let url = format!("{}/graphql", self.api_url);
+        let response = self
+            .client
+            .post(url)
+            .json(&request_body)
+            .header("ApiKey", &self.api_key)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let response: Response<find_studios_query::ResponseData> = response.json().await?;
+        let studios = response.data.unwrap();
+        Ok(studios.find_studios.studios)
+    }
+
+    /// Fetches the saved scene marker filters.
+    pub async fn find_saved_filters(&self) -> Result<Vec<FindSavedFiltersQueryFindSavedFilters>> {
+        let request_body =
+            FindSavedFiltersQuery::build_query(find_saved_filters_query::Variables {});
+        let url = format!("{}/graphql", self.api_url);
+        let response = self
+            .client
+            .post(url)
+            .json(&request_body)
+            .header("ApiKey", &self.api_key)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let response: Response<find_saved_filters_query::ResponseData> = response.json().await?;
+        let filters = response.data.unwrap();
+        Ok(filters.find_saved_filters)
+    }
 }